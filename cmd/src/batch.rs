@@ -0,0 +1,199 @@
+// Running a command over many files on a pool of threads
+
+use std::any::Any;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Output of the job on one file, printed in one piece so that parallel jobs do not interleave
+#[derive(Default)]
+pub struct Output {
+  text: String,
+  to_stderr: bool,
+}
+
+impl Output {
+  pub fn line<T: Display>(&mut self, line: T) {
+    self.text.push_str(&line.to_string());
+    self.text.push('\n');
+  }
+
+  /// Print to stderr, when stdout carries the file itself
+  pub fn redirect_to_stderr(&mut self) {
+    self.to_stderr = true;
+  }
+}
+
+fn is_glob(pattern: &str) -> bool {
+  pattern.contains(['*', '?', '['])
+}
+
+fn is_png(path: &Path) -> bool {
+  path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+}
+
+/// PNG files under `dir` and its subdirectories, in name order. Symlinked directories are not followed.
+fn walk(dir: &Path, files: &mut Vec<String>) -> Result<(), String> {
+  let entries = utils::fs::read_dir(dir).map_err(|e| format!("Failed to read directory {}", e))?;
+
+  for entry in entries {
+    let path = entry.path();
+
+    match entry.file_type() {
+      Ok(file_type) if file_type.is_dir() => walk(&path, files)?,
+      _ if is_png(&path) => files.push(path.to_string_lossy().into_owned()),
+      _ => {},
+    }
+  }
+
+  Ok(())
+}
+
+/// Files to run on: paths as given, the PNG files under directories, and the matches of glob patterns.
+/// A file given twice is run once.
+pub fn expand(patterns: &[String]) -> Result<Vec<String>, String> {
+  let mut files = vec![];
+
+  for pattern in patterns {
+    if is_glob(pattern) {
+      let matches = glob::glob(pattern).map_err(|e| format!("Invalid pattern {}: {}", pattern, e))?;
+      let before = files.len();
+
+      for path in matches {
+        let path = path.map_err(|e| e.to_string())?;
+
+        match path.is_dir() {
+          true => walk(&path, &mut files)?,
+          false => files.push(path.to_string_lossy().into_owned()),
+        }
+      }
+
+      if files.len() == before {
+        return Err(format!("No files match {}", pattern))
+      }
+    } else if Path::new(pattern).is_dir() {
+      walk(Path::new(pattern), &mut files)?;
+    } else {
+      files.push(pattern.clone());
+    }
+  }
+
+  let mut seen = HashSet::new();
+  files.retain(|file| seen.insert(file.clone()));
+
+  Ok(files)
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+  match panic.downcast::<String>() {
+    Ok(message) => *message,
+    Err(panic) => panic.downcast_ref::<&str>().map_or(String::from("Unknown error"), |message| message.to_string()),
+  }
+}
+
+/// Run `job` on every file with up to `jobs` threads, printing the output of each file once it is done.
+/// With more than one file, each output is headed by its file name and a summary follows.
+/// Returns whether the job succeeded on every file.
+pub fn run<F>(files: &[String], jobs: usize, job: F) -> bool
+where
+  F: Fn(&str, &mut Output) -> Result<(), String> + Sync,
+{
+  let many = files.len() > 1;
+  let next = AtomicUsize::new(0);
+  let failures = Mutex::new(vec![]);
+
+  thread::scope(|scope| {
+    for _ in 0..jobs.clamp(1, files.len().max(1)) {
+      scope.spawn(|| loop {
+        let i = next.fetch_add(1, Ordering::Relaxed);
+        let Some(file) = files.get(i) else { break };
+
+        let mut output = Output::default();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| job(file, &mut output)))
+          .unwrap_or_else(|panic| Err(format!("panicked: {}", panic_message(panic))));
+
+        {
+          let mut stdout = std::io::stdout().lock();
+
+          if many {
+            let _ = writeln!(stdout, "==> {} <==", file);
+          }
+
+          if output.to_stderr {
+            eprint!("{}", output.text);
+          } else {
+            let _ = stdout.write_all(output.text.as_bytes());
+          }
+
+          if let Err(e) = &result {
+            eprintln!("{}", e);
+          }
+        }
+
+        if let Err(e) = result {
+          failures.lock().unwrap().push((i, e));
+        }
+      });
+    }
+  });
+
+  let mut failures = failures.into_inner().unwrap();
+  failures.sort_by_key(|(i, _)| *i);
+
+  if many {
+    println!();
+    println!("{} files: {} succeeded, {} failed", files.len(), files.len() - failures.len(), failures.len());
+
+    for (i, e) in failures.iter() {
+      println!("Failed: {}: {}", files[*i], e);
+    }
+  }
+
+  failures.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  #[test]
+  fn test_expand() {
+    let dir = std::env::temp_dir().join(format!("png-me-batch-{}", std::process::id()));
+    fs::create_dir_all(dir.join("nested")).unwrap();
+    for name in ["b.png", "a.PNG", "notes.txt", "nested/c.png"] {
+      fs::write(dir.join(name), b"").unwrap();
+    }
+
+    let root = dir.to_string_lossy().into_owned();
+    let files = expand(&[root.clone(), format!("{}/*.png", root), String::from("missing.png")]).unwrap();
+
+    let names: Vec<String> = files.iter().map(|f| f.trim_start_matches(&root).to_string()).collect();
+    assert_eq!(names, vec!["/a.PNG", "/b.png", "/nested/c.png", "missing.png"]);
+    assert!(expand(&[format!("{}/*.gif", root)]).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_run_reports_failures() {
+    let files: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+
+    let ok = run(&files, 4, |file, output| {
+      output.line(file);
+      match file.parse::<u32>().unwrap() % 7 {
+        0 => Err(String::from("failed")),
+        3 => panic!("panicked"),
+        _ => Ok(()),
+      }
+    });
+    assert!(!ok);
+
+    assert!(run(&files[1..2], 4, |_, _| Ok(())));
+  }
+}
@@ -1,26 +1,544 @@
-use clap::{Args, Subcommand};
+use clap::{Args, Subcommand, ValueEnum};
+use png::color_type::ColorType;
+use png::message::compress::CompressionMethod;
+use png::message::split::DEFAULT_PART_LEN;
+use png::netpbm::NetpbmFormat;
+use png::qoi::QoiColorspace;
+use png::stego::{Channel, LsbOptions};
 
 #[derive(Subcommand)]
 pub enum Commands {
   Info(InfoArgs),
+  Chunks(ChunksArgs),
+  Diff(DiffArgs),
+  Check(CheckArgs),
   Set(SetArgs),
+  #[command(alias = "read")]
+  Decode(DecodeArgs),
+  Print(PrintArgs),
   Remove(RemoveArgs),
+  Convert(ConvertArgs),
+  Export(ExportArgs),
+  Import(ImportArgs),
+  Quantize(QuantizeArgs),
+  Optimize(OptimizeArgs),
+  Reduce(ReduceArgs),
+  Strip(StripArgs),
+  Repair(RepairArgs),
+  Keygen(KeygenArgs),
+  Sign(SignArgs),
+  Verify(VerifyArgs),
+  Embed(EmbedArgs),
+  Extract(ExtractArgs),
+  Attach(AttachArgs),
+  Attachments(AttachmentsArgs),
+  Detach(DetachArgs),
+}
+
+impl Commands {
+  /// Files, directories and glob patterns the command runs on
+  pub fn files(&self) -> &[String] {
+    match self {
+      Commands::Info(args) => &args.files,
+      Commands::Chunks(args) => &args.files,
+      Commands::Diff(_) => &[],
+      Commands::Check(args) => &args.files,
+      Commands::Set(args) => &args.files,
+      Commands::Decode(args) => &args.files,
+      Commands::Print(args) => &args.files,
+      Commands::Remove(args) => &args.files,
+      Commands::Convert(args) => &args.files,
+      Commands::Export(args) => &args.files,
+      Commands::Import(_) => &[],
+      Commands::Quantize(args) => &args.files,
+      Commands::Optimize(args) => &args.files,
+      Commands::Reduce(args) => &args.files,
+      Commands::Strip(args) => &args.files,
+      Commands::Repair(args) => &args.files,
+      Commands::Keygen(_) => &[],
+      Commands::Sign(args) => &args.files,
+      Commands::Verify(args) => &args.files,
+      Commands::Embed(args) => &args.files,
+      Commands::Extract(args) => &args.files,
+      Commands::Attach(args) => &args.files,
+      Commands::Attachments(args) => &args.files,
+      Commands::Detach(args) => &args.files,
+    }
+  }
+
+  /// Options of the commands writing the file back
+  pub fn write_args(&self) -> Option<&WriteArgs> {
+    match self {
+      Commands::Set(args) => Some(&args.write),
+      Commands::Remove(args) => Some(&args.write),
+      Commands::Convert(args) => Some(&args.write),
+      Commands::Export(args) => Some(&args.write),
+      Commands::Quantize(args) => Some(&args.write),
+      Commands::Optimize(args) => Some(&args.write),
+      Commands::Reduce(args) => Some(&args.write),
+      Commands::Strip(args) => Some(&args.write),
+      Commands::Repair(args) => Some(&args.write),
+      Commands::Sign(args) => Some(&args.write),
+      Commands::Embed(args) => Some(&args.write),
+      Commands::Attach(args) => Some(&args.write),
+      Commands::Detach(args) => Some(&args.write),
+      _ => None,
+    }
+  }
 }
 
 #[derive(Args)]
 pub struct InfoArgs {
-  pub file: String,
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  /// Output format
+  #[arg(long, value_enum, default_value = "text")]
+  pub format: InfoFormat,
+}
+
+#[derive(Args)]
+pub struct ChunksArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin
+  #[arg(required = true)]
+  pub files: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct DiffArgs {
+  /// PNG file to compare from. `-` reads stdin
+  pub a: String,
+  /// PNG file to compare to
+  pub b: String,
+}
+
+#[derive(Args)]
+pub struct CheckArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin
+  #[arg(required = true)]
+  pub files: Vec<String>,
 }
 
 #[derive(Args)]
 pub struct SetArgs {
-  pub file: String,
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
   pub chunk_name: String,
+  /// Message to store
+  #[arg(short, long)]
   pub message: String,
+  /// Compress the message
+  #[arg(long, value_enum)]
+  pub compress: Option<CompressionArg>,
+  /// Encrypt the message with a passphrase
+  #[arg(long, requires = "passphrase")]
+  pub encrypt: bool,
+  #[arg(long, env = "PNG_ME_PASSPHRASE", hide_env_values = true)]
+  pub passphrase: Option<String>,
+  /// Max data length of each chunk, larger messages are split across several chunks
+  #[arg(long, default_value_t = DEFAULT_PART_LEN)]
+  pub part_size: usize,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct DecodeArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  pub chunk_type: String,
+  /// Decrypt a message stored with `set --encrypt`
+  #[arg(long, requires = "passphrase")]
+  pub decrypt: bool,
+  #[arg(long, env = "PNG_ME_PASSPHRASE", hide_env_values = true)]
+  pub passphrase: Option<String>,
+}
+
+#[derive(Args)]
+pub struct PrintArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin
+  #[arg(required = true)]
+  pub files: Vec<String>,
 }
 
 #[derive(Args)]
 pub struct RemoveArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  pub chunk_name: String,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct ConvertArgs {
+  /// PNG or QOI files, directories searched recursively for PNG files, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  #[arg(long, value_enum)]
+  pub color_type: Option<ColorTypeArg>,
+  #[arg(long, default_value_t = 8)]
+  pub bit_depth: u8,
+  /// Format to write. A file changing format is written next to the input with the new extension, unless `--output` is given
+  #[arg(long, value_enum, default_value = "png")]
+  pub to: ImageFormatArg,
+  /// Colorspace flag of the QOI files written, instead of the one given by the sRGB and gAMA chunks
+  #[arg(long, value_enum)]
+  pub colorspace: Option<QoiColorspaceArg>,
+  /// Keep unknown chunks marked unsafe to copy, which are dropped by default once the image data changes
+  #[arg(long)]
+  pub keep_unsafe: bool,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  /// Written next to the PNG file with the extension of the format, unless `--output` is given
+  #[arg(long, value_enum)]
+  pub format: NetpbmFormatArg,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct ImportArgs {
+  /// PGM, PPM or PAM file. `-` reads stdin
   pub file: String,
+  /// PNG file to write. `-` writes stdout
+  pub output: String,
+}
+
+#[derive(Args)]
+pub struct QuantizeArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  /// Max palette entries, 1 - 256
+  #[arg(long, default_value_t = 256)]
+  pub colors: usize,
+  /// Keep unknown chunks marked unsafe to copy, which are dropped by default once the image data changes
+  #[arg(long)]
+  pub keep_unsafe: bool,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct OptimizeArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  /// Ancillary chunks to keep, comma separated. All chunks are kept if omitted
+  #[arg(long, value_delimiter = ',')]
+  pub keep: Option<Vec<String>>,
+  /// Reduce the color type and bit depth losslessly first
+  #[arg(long)]
+  pub reduce: bool,
+  /// Remove unused and duplicate palette entries of indexed images, and sort them by usage
+  #[arg(long)]
+  pub clean_palette: bool,
+  /// Keep unknown chunks marked unsafe to copy, which are dropped by default once the image data changes
+  #[arg(long)]
+  pub keep_unsafe: bool,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct ReduceArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  /// Keep unknown chunks marked unsafe to copy, which are dropped by default once the image data changes
+  #[arg(long)]
+  pub keep_unsafe: bool,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct StripArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  /// Chunks to keep in any case, comma separated
+  #[arg(long, value_delimiter = ',')]
+  pub keep: Vec<String>,
+  /// Also drop the chunks affecting rendering, such as gAMA, iCCP and tRNS
+  #[arg(long)]
+  pub drop_all_ancillary: bool,
+  /// Keep every chunk marked safe to copy
+  #[arg(long)]
+  pub keep_safe_to_copy: bool,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct RepairArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct KeygenArgs {
+  /// Private key file to write, the public key is written next to it with a `.pub` extension
+  pub key_file: String,
+}
+
+#[derive(Args)]
+pub struct SignArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
   pub chunk_name: String,
+  /// Private key file written by `keygen`
+  #[arg(long)]
+  pub key: String,
+  /// Sign the critical chunks too, so that any change to the image fails verification
+  #[arg(long)]
+  pub critical: bool,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct VerifyArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  pub chunk_name: String,
+  /// Public key file written by `keygen`
+  #[arg(long)]
+  pub pubkey: String,
+}
+
+#[derive(Args)]
+pub struct EmbedArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  pub message: String,
+  #[command(flatten)]
+  pub lsb: LsbArgs,
+  /// Compress the message
+  #[arg(long, value_enum)]
+  pub compress: Option<CompressionArg>,
+  /// Encrypt the message with a passphrase
+  #[arg(long, requires = "passphrase")]
+  pub encrypt: bool,
+  #[arg(long, env = "PNG_ME_PASSPHRASE", hide_env_values = true)]
+  pub passphrase: Option<String>,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct ExtractArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  #[command(flatten)]
+  pub lsb: LsbArgs,
+  /// Decrypt a message embedded with `--encrypt`
+  #[arg(long, requires = "passphrase")]
+  pub decrypt: bool,
+  #[arg(long, env = "PNG_ME_PASSPHRASE", hide_env_values = true)]
+  pub passphrase: Option<String>,
+}
+
+#[derive(Args)]
+pub struct AttachArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  /// File to attach, repeated for several; an attachment of the same name is replaced
+  #[arg(long = "path", required = true)]
+  pub paths: Vec<String>,
+  /// MIME type, guessed from the extension if omitted
+  #[arg(long)]
+  pub mime: Option<String>,
+  /// Compress the attached files
+  #[arg(long, value_enum)]
+  pub compress: Option<CompressionArg>,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct AttachmentsArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin
+  #[arg(required = true)]
+  pub files: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct DetachArgs {
+  /// PNG files, directories searched recursively for them, or glob patterns. `-` reads stdin and writes stdout unless `--output` is given
+  #[arg(required = true)]
+  pub files: Vec<String>,
+  /// Name of an attachment to remove, repeated for several; all of them if omitted
+  #[arg(long = "name")]
+  pub names: Vec<String>,
+  /// Directory to write the attachments to before removing them
+  #[arg(long)]
+  pub extract_to: Option<String>,
+  #[command(flatten)]
+  pub write: WriteArgs,
+}
+
+#[derive(Args)]
+pub struct WriteArgs {
+  /// Write the result to this file instead of replacing the input; a directory when running on several files
+  #[arg(short, long)]
+  pub output: Option<String>,
+  /// Keep the file being replaced as <file>.bak
+  #[arg(long)]
+  pub backup: bool,
+}
+
+#[derive(Args)]
+pub struct LsbArgs {
+  /// Hide the message in the least significant bits of the samples
+  #[arg(long, required = true)]
+  pub lsb: bool,
+  /// Low bits used in each sample
+  #[arg(long, default_value_t = 1)]
+  pub bits: u8,
+  /// Channels carrying bits, comma separated. The color channels if omitted
+  #[arg(long, value_enum, value_delimiter = ',')]
+  pub channels: Vec<ChannelArg>,
+  /// Key shuffling the order of the pixels, needed again to extract
+  #[arg(long)]
+  pub key: Option<String>,
+}
+
+impl From<&LsbArgs> for LsbOptions {
+  fn from(args: &LsbArgs) -> Self {
+    LsbOptions {
+      bits: args.bits,
+      channels: args.channels.iter().cloned().map(|c| c.into()).collect(),
+      key: args.key.clone(),
+    }
+  }
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum InfoFormat {
+  Text,
+  Json,
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum ChannelArg {
+  R,
+  G,
+  B,
+  Gray,
+  A,
+}
+
+impl From<ChannelArg> for Channel {
+  fn from(arg: ChannelArg) -> Self {
+    match arg {
+      ChannelArg::R => Channel::Red,
+      ChannelArg::G => Channel::Green,
+      ChannelArg::B => Channel::Blue,
+      ChannelArg::Gray => Channel::Gray,
+      ChannelArg::A => Channel::Alpha,
+    }
+  }
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum NetpbmFormatArg {
+  Pgm,
+  Ppm,
+  Pam,
+}
+
+impl From<NetpbmFormatArg> for NetpbmFormat {
+  fn from(arg: NetpbmFormatArg) -> Self {
+    match arg {
+      NetpbmFormatArg::Pgm => NetpbmFormat::Pgm,
+      NetpbmFormatArg::Ppm => NetpbmFormat::Ppm,
+      NetpbmFormatArg::Pam => NetpbmFormat::Pam,
+    }
+  }
+}
+
+#[derive(Clone, PartialEq, ValueEnum)]
+pub enum ImageFormatArg {
+  Png,
+  Qoi,
+}
+
+impl ImageFormatArg {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      ImageFormatArg::Png => "png",
+      ImageFormatArg::Qoi => "qoi",
+    }
+  }
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum QoiColorspaceArg {
+  Srgb,
+  Linear,
+}
+
+impl From<QoiColorspaceArg> for QoiColorspace {
+  fn from(arg: QoiColorspaceArg) -> Self {
+    match arg {
+      QoiColorspaceArg::Srgb => QoiColorspace::Srgb,
+      QoiColorspaceArg::Linear => QoiColorspace::Linear,
+    }
+  }
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum ColorTypeArg {
+  Gray,
+  Rgb,
+  Palette,
+  GrayAlpha,
+  Rgba,
+}
+
+impl From<ColorTypeArg> for ColorType {
+  fn from(arg: ColorTypeArg) -> Self {
+    match arg {
+      ColorTypeArg::Gray => ColorType::Grayscale,
+      ColorTypeArg::Rgb => ColorType::Rgb,
+      ColorTypeArg::Palette => ColorType::PaletteIndex,
+      ColorTypeArg::GrayAlpha => ColorType::GrayscaleWithAlpha,
+      ColorTypeArg::Rgba => ColorType::RgbWithAlpha,
+    }
+  }
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum CompressionArg {
+  Deflate,
+  Zstd,
+}
+
+impl From<CompressionArg> for CompressionMethod {
+  fn from(arg: CompressionArg) -> Self {
+    match arg {
+      CompressionArg::Deflate => CompressionMethod::Deflate,
+      CompressionArg::Zstd => CompressionMethod::Zstd,
+    }
+  }
 }
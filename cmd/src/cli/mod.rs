@@ -8,12 +8,15 @@ use clap::Parser;
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
   #[command(subcommand)]
-  command: Commands,
+  pub command: Commands,
+  /// Files processed in parallel, the number of CPUs by default
+  #[arg(short, long, global = true)]
+  pub jobs: Option<usize>,
 }
 
-pub fn parse() -> Commands {
+pub fn parse() -> Cli {
   // ruSt
-  Cli::parse().command
+  Cli::parse()
 
   // let tag = &args.tag;
 
@@ -1,84 +1,760 @@
+mod batch;
 mod cli;
 
+use std::collections::HashSet;
+use std::path::Path;
+use std::process;
 use std::str::FromStr;
+use std::thread;
+use batch::Output;
 use cli::parse;
-use cli::commands::Commands;
+use cli::commands::{Commands, DiffArgs, ImageFormatArg, ImportArgs, InfoFormat, KeygenArgs, WriteArgs};
 
-use png::Png;
+use png::{Png, UnsafeChunks};
+use png::color_type::ColorType;
+use png::attachment::{self, Attachment};
+use png::check;
+use png::image::Image;
+use png::message::{self, Message};
+use png::netpbm::NetpbmFormat;
+use png::qoi;
+use png::repair;
+use png::sign;
+use png::stego::LsbOptions;
+use png::strip::StripOptions;
 use png::chunk::{Chunk, ChunkType};
-use utils::fs::{read_file_buffer, write_buffer_to_file};
+use png::info::ChunkFields;
+use utils::fs::{self, STDIO_PATH};
 
 fn main() {
-  let command = parse();
+  let cli = parse();
+  let command = cli.command;
+
+  if let Commands::Keygen(args) = &command {
+    if let Err(e) = keygen(args) {
+      eprintln!("{}", e);
+      process::exit(1);
+    }
+    return;
+  }
+
+  if let Commands::Import(args) = &command {
+    if let Err(e) = import(args) {
+      eprintln!("{}", e);
+      process::exit(1);
+    }
+    return;
+  }
+
+  if let Commands::Diff(args) = &command {
+    // like diff(1): 1 when the files differ, 2 when they can not be compared
+    match diff(args) {
+      Ok(true) => {},
+      Ok(false) => process::exit(1),
+      Err(e) => {
+        eprintln!("{}", e);
+        process::exit(2);
+      },
+    }
+    return;
+  }
+
+  let files = match batch::expand(command.files()) {
+    Ok(files) => files,
+    Err(e) => {
+      eprintln!("{}", e);
+      process::exit(1);
+    },
+  };
+
+  if files.len() > 1 && files.iter().any(|file| file == STDIO_PATH) {
+    eprintln!("{} can not be combined with other files", STDIO_PATH);
+    process::exit(1);
+  }
+
+  if let Err(e) = check_targets(command.write_args(), &files) {
+    eprintln!("{}", e);
+    process::exit(1);
+  }
+
+  let jobs = cli.jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+
+  let succeeded = batch::run(&files, jobs, |filepath, out| {
+    let target = target_of(command.write_args(), filepath)?;
+    run(&command, filepath, &target, out)
+  });
+
+  if !succeeded {
+    process::exit(1);
+  }
+}
+
+/// Run `command` on one file, writing the result to `target` if the command changes the file
+fn run(command: &Commands, filepath: &str, target: &str, out: &mut Output) -> Result<(), String> {
+  if target == STDIO_PATH && command.write_args().is_some() {
+    out.redirect_to_stderr();
+  }
 
   match command {
     Commands::Info(args) => {
-      let filepath = args.file;
+      let (_, png) = read_png(filepath)?;
+
+      match args.format {
+        InfoFormat::Text => out.line(&png),
+        InfoFormat::Json => out.line(serde_json::to_string_pretty(&png.info()).expect("Chunk info is serializable")),
+      }
+    },
+    Commands::Chunks(_) => {
+      let buffer = fs::read(filepath).map_err(|e| format!("Failed to read {}", e))?;
 
-      let buffer = read_file_buffer(&filepath);
+      if !buffer.starts_with(&Png::SIGNATURE) {
+        return Err(String::from("Not a valid png format: invalid signature"))
+      }
 
-      let png = Png::try_from(buffer.as_slice())
-        .expect("Not a valid png format");
-      
-      println!("{}", png.to_string());
+      chunk_table(&buffer, out);
     },
     Commands::Set(args) => {
-      let filepath = args.file;
-      let chunk_name = args.chunk_name;
-      let message = args.message;
+      let chunk_name = &args.chunk_name;
+
+      let passphrase = args.passphrase.as_deref().filter(|_| args.encrypt);
+
+      let data = message::pack(args.message.as_bytes(), args.compress.clone().map(|c| c.into()), passphrase)
+        .map_err(|e| format!("Failed to pack message: {}", e))?;
+
+      let (_, mut png) = read_png(filepath)?;
+
+      let chunk_type = ChunkType::from_str(chunk_name)
+        .map_err(|e| format!("Invalid chunk type {}: {}", chunk_name, e))?;
+
+      let parts = message::split::split(&data, args.part_size)
+        .map_err(|e| format!("Failed to split message: {}", e))?;
+
+      let count = parts.len();
+      let chunks = parts.into_iter().map(|part| Chunk::new(chunk_type, part)).collect();
+
+      // replaces the parts of a previous message too
+      if png.replace_chunks(chunk_name, chunks).is_err() {
+        return Err(String::from("Failed: can not found IEND chunk"))
+      }
+
+      if count > 1 {
+        out.line(format!("Split message into {} {} chunks", count, chunk_name));
+      }
+
+      save(&png.as_bytes(), target, &args.write)?;
+      out.line("Success");
+    },
+    Commands::Decode(args) => {
+      let chunk_type = &args.chunk_type;
+
+      let (_, png) = read_png(filepath)?;
+
+      let passphrase = args.passphrase.as_deref().filter(|_| args.decrypt);
+      let message = png.message(chunk_type, passphrase)
+        .and_then(|bytes| Ok(String::from_utf8(bytes)?))
+        .map_err(|e| format!("Failed to decode chunk {}: {}", chunk_type, e))?;
+
+      out.line(message);
+    },
+    Commands::Print(_) => {
+      let (_, png) = read_png(filepath)?;
+
+      let messages = png.messages();
+
+      if messages.is_empty() {
+        out.line("No messages");
+      }
+
+      for (chunk_type, message) in messages.iter() {
+        match message {
+          Message::Text(text) => out.line(format!("{}: {}", chunk_type, text)),
+          Message::Encrypted => out.line(format!("{}: (encrypted)", chunk_type)),
+        };
+      }
+    },
+    Commands::Remove(args) => {
+      let chunk_name = &args.chunk_name;
+
+      let (_, mut png) = read_png(filepath)?;
+
+      png.remove_chunk(chunk_name)
+        .map_err(|e| format!("Failed to remove chunk {}: {}", chunk_name, e))?;
 
-      let buffer = read_file_buffer(&filepath);
+      save(&png.as_bytes(), target, &args.write)?;
+      out.line(format!("Successfully removed chunk {}", chunk_name));
+    },
+    Commands::Convert(args) => {
+      let buffer = fs::read(filepath).map_err(|e| format!("Failed to read {}", e))?;
+      let from_qoi = buffer.starts_with(qoi::MAGIC);
+
+      if args.color_type.is_none() && args.to == ImageFormatArg::Png && !from_qoi {
+        return Err(String::from("Nothing to convert: give --color-type or --to qoi"))
+      }
+
+      let mut png = match from_qoi {
+        true => Png::from_qoi(&buffer).map_err(|e| format!("Failed to convert: {}", e))?,
+        false => Png::try_from(buffer.as_slice()).map_err(|e| format!("Not a valid png format: {}", e))?,
+      };
+
+      if let Some(color_type) = &args.color_type {
+        let color_type: ColorType = color_type.clone().into();
+        let bit_depth = args.bit_depth;
+
+        let discarded = png.image()
+          .and_then(|image| image.convert(color_type.clone(), bit_depth))
+          .and_then(|image| png.set_image(&image, unsafe_chunks(args.keep_unsafe)))
+          .map_err(|e| format!("Failed to convert: {}", e))?;
+
+        report_discarded(&discarded, out);
+        out.line(format!("Successfully converted to {} ({} bit)", color_type, bit_depth));
+      }
+
+      let bytes = match args.to {
+        ImageFormatArg::Png => png.as_bytes(),
+        ImageFormatArg::Qoi => png.to_qoi(args.colorspace.clone().map(Into::into)).map_err(|e| format!("Failed to convert: {}", e))?,
+      };
+
+      let changes_format = from_qoi != (args.to == ImageFormatArg::Qoi);
+
+      if changes_format {
+        let target = renamed_target(target, &args.write, args.to.extension());
+        save(&bytes, &target, &args.write)?;
+        out.line(format!("Converted to {}", target));
+      } else {
+        save(&bytes, target, &args.write)?;
+      }
+    },
+    Commands::Export(args) => {
+      let format: NetpbmFormat = args.format.clone().into();
 
-      let mut png = Png::try_from(buffer.as_slice())
-        .expect("Not a valid png format");
+      let target = renamed_target(target, &args.write, format.extension());
 
-      let chunk = png.get_chunk_mut(&chunk_name);
-      
-      match chunk {
-        Some(c) => {
-          c.set_data(message.as_bytes());
+      let (_, png) = read_png(filepath)?;
+
+      let bytes = png.image()
+        .and_then(|image| image.to_netpbm(format))
+        .map_err(|e| format!("Failed to export: {}", e))?;
+
+      save(&bytes, &target, &args.write)?;
+      out.line(format!("Exported {} ({} bytes)", target, bytes.len()));
+    },
+    Commands::Import(_) => unreachable!("import reads a Netpbm file"),
+    Commands::Quantize(args) => {
+      let (_, mut png) = read_png(filepath)?;
+
+      let (entries, discarded) = png.image()
+        .and_then(|image| image.quantize(args.colors))
+        .and_then(|image| {
+          let discarded = png.set_image(&image, unsafe_chunks(args.keep_unsafe))?;
+          Ok((image.palette().map_or(0, |p| p.len()), discarded))
+        })
+        .map_err(|e| format!("Failed to quantize: {}", e))?;
+
+      save(&png.as_bytes(), target, &args.write)?;
+      report_discarded(&discarded, out);
+      out.line(format!("Successfully quantized to {} palette entries", entries));
+    },
+    Commands::Optimize(args) => {
+      let (buffer, mut png) = read_png(filepath)?;
+
+      if let Some(keep) = &args.keep {
+        let removed = png.remove_chunks_by(|chunk| {
+          let chunk_type = chunk.chunk_type();
+          !chunk_type.is_critical() && !keep.contains(&chunk_type.to_string())
+        });
+
+        for chunk in removed.iter() {
+          out.line(format!("Removed chunk {}", chunk.chunk_type()));
+        }
+      }
+
+      let unsafe_chunks = unsafe_chunks(args.keep_unsafe);
+
+      if args.reduce {
+        let reduced = png.reduce(unsafe_chunks)
+          .map_err(|e| format!("Failed to reduce: {}", e))?;
+
+        if let Some(discarded) = reduced {
+          report_discarded(&discarded, out);
+          out.line(format!("Reduced to {}", format_of(&png)));
+        }
+      }
+
+      let indexed = png.header_chunk().is_some_and(|header| header.color_type() == ColorType::PaletteIndex);
+
+      if args.clean_palette && indexed {
+        let cleanup = png.optimize_palette(unsafe_chunks)
+          .map_err(|e| format!("Failed to clean palette: {}", e))?;
+
+        report_discarded(&cleanup.discarded, out);
+        out.line(format!(
+          "Palette: {} -> {} entries ({} unused, {} duplicates)",
+          cleanup.original_entries,
+          cleanup.entries,
+          cleanup.unused,
+          cleanup.duplicates,
+        ));
+      }
+
+      let result = png.optimize(unsafe_chunks)
+        .map_err(|e| format!("Failed to optimize: {}", e))?;
+
+      let bytes = png.as_bytes();
+      save(&bytes, target, &args.write)?;
+      report_discarded(&result.discarded, out);
+
+      match result.best {
+        Some((strategy, level)) => out.line(format!("Filter: {}, compression level: {}", strategy, level)),
+        None => out.line("Image data is already optimal"),
+      };
+      out.line(format!("IDAT: {} -> {} bytes", result.original_len, result.optimized_len));
+      out.line(format!("File: {} -> {} bytes", buffer.len(), bytes.len()));
+    },
+    Commands::Reduce(args) => {
+      let (buffer, mut png) = read_png(filepath)?;
+
+      let before = format_of(&png);
+
+      let reduced = png.reduce(unsafe_chunks(args.keep_unsafe))
+        .map_err(|e| format!("Failed to reduce: {}", e))?;
+
+      match reduced {
+        Some(discarded) => {
+          let bytes = png.as_bytes();
+          save(&bytes, target, &args.write)?;
+          report_discarded(&discarded, out);
+          out.line(format!("Reduced {} -> {}", before, format_of(&png)));
+          out.line(format!("File: {} -> {} bytes", buffer.len(), bytes.len()));
         },
         None => {
-          let chunk_type = ChunkType::from_str(&chunk_name)
-            .expect("Invalid chunk type");
-          let data = message.into_bytes();
-          let chunk = Chunk::new(chunk_type, data);
-          // find IEND
-          let end_pos = png.chunk_position("IEND");
-
-          match end_pos {
-            Some(pos) => {
-              png.insert_chunk(pos, chunk);
-            },
-            None => {
-              eprintln!("Failed: can not found IEND chunk");
-              return;
-            }
-          }
+          save_unchanged(&buffer, filepath, target)?;
+          out.line(format!("{} is already the cheapest lossless format", before));
         },
       };
+    },
+    Commands::Strip(args) => {
+      let (buffer, mut png) = read_png(filepath)?;
+
+      let keep = args.keep.iter()
+        .map(|name| ChunkType::from_str(name))
+        .collect::<Result<Vec<ChunkType>, _>>()
+        .map_err(|e| format!("Invalid chunk type: {}", e))?;
 
-      write_buffer_to_file(&png.as_bytes()[..], &filepath);
-      println!("Success");
+      let options = StripOptions {
+        keep,
+        drop_all_ancillary: args.drop_all_ancillary,
+        keep_safe_to_copy: args.keep_safe_to_copy,
+      };
+
+      let removed = png.strip(&options);
+
+      if removed.is_empty() {
+        save_unchanged(&buffer, filepath, target)?;
+        out.line("Nothing to strip");
+        return Ok(());
+      }
+
+      for chunk in removed.iter() {
+        out.line(format!("Removed chunk {} ({} bytes)", chunk.chunk_type(), chunk.length()));
+      }
+
+      let bytes = png.as_bytes();
+      save(&bytes, target, &args.write)?;
+      out.line(format!("Removed {} chunks, file: {} -> {} bytes", removed.len(), buffer.len(), bytes.len()));
     },
-    Commands::Remove(args) => {
-      let filepath = args.file;
-      let chunk_name = args.chunk_name;
+    Commands::Check(_) => {
+      let buffer = fs::read(filepath).map_err(|e| format!("Failed to read {}", e))?;
+      let problems = check::check(&buffer);
 
-      let buffer = read_file_buffer(&filepath);
+      if problems.is_empty() {
+        out.line("OK");
+        return Ok(());
+      }
 
-      let mut png = Png::try_from(buffer.as_slice())
-        .expect("Not a valid png format");
+      for problem in problems.iter() {
+        out.line(problem);
+      }
 
-      match png.remove_chunk(&chunk_name) {
-        Ok(_) => {
-          write_buffer_to_file(&png.as_bytes()[..], &filepath);
-          println!("Successfully removed chunk {}", chunk_name);
-        },
-        Err(e) => {
-          println!("Failed to remove chunk {}: {}", chunk_name, e);
-        },
+      return Err(format!("{} problems found", problems.len()))
+    },
+    Commands::Repair(args) => {
+      let buffer = fs::read(filepath).map_err(|e| format!("Failed to read {}", e))?;
+
+      let repaired = repair::repair(&buffer).map_err(|e| format!("Failed to repair: {}", e))?;
+
+      if repaired.log.is_empty() {
+        save_unchanged(&buffer, filepath, target)?;
+        out.line("Nothing to repair");
+        return Ok(());
+      }
+
+      for change in repaired.log.iter() {
+        out.line(change);
+      }
+
+      let bytes = repaired.png.as_bytes();
+      save(&bytes, target, &args.write)?;
+      out.line(format!("Made {} repairs, file: {} -> {} bytes", repaired.log.len(), buffer.len(), bytes.len()));
+    },
+    Commands::Diff(_) => unreachable!("diff runs on its two files"),
+    Commands::Keygen(_) => unreachable!("keygen does not run on files"),
+    Commands::Sign(args) => {
+      let chunk_name = &args.chunk_name;
+
+      let pem = fs::read(&args.key).map_err(|e| format!("Failed to read key {}", e))?;
+      let key = sign::signing_key_from_pem(&String::from_utf8_lossy(&pem));
+
+      let (_, mut png) = read_png(filepath)?;
+
+      key.and_then(|key| png.sign(chunk_name, &key, args.critical))
+        .map_err(|e| format!("Failed to sign chunk {}: {}", chunk_name, e))?;
+
+      save(&png.as_bytes(), target, &args.write)?;
+      out.line(format!("Signed chunk {}{}", chunk_name, if args.critical { " and the critical chunks" } else { "" }));
+    },
+    Commands::Verify(args) => {
+      let chunk_name = &args.chunk_name;
+
+      let pem = fs::read(&args.pubkey).map_err(|e| format!("Failed to read key {}", e))?;
+      let key = sign::verifying_key_from_pem(&String::from_utf8_lossy(&pem));
+
+      let (_, png) = read_png(filepath)?;
+
+      let with_critical = key.and_then(|key| png.verify(chunk_name, &key))
+        .map_err(|e| format!("Failed to verify chunk {}: {}", chunk_name, e))?;
+
+      match with_critical {
+        true => out.line(format!("Valid signature of chunk {} and the critical chunks", chunk_name)),
+        false => out.line(format!("Valid signature of chunk {}", chunk_name)),
       };
     },
+    Commands::Embed(args) => {
+      let options: LsbOptions = (&args.lsb).into();
+
+      let passphrase = args.passphrase.as_deref().filter(|_| args.encrypt);
+
+      let (_, mut png) = read_png(filepath)?;
+
+      let (len, capacity, discarded) = message::pack(args.message.as_bytes(), args.compress.clone().map(|c| c.into()), passphrase)
+        .and_then(|payload| {
+          let image = png.image()?;
+          let capacity = image.lsb_capacity(&options)?;
+          let stego = image.embed_lsb(&payload, &options)?;
+          let discarded = png.set_image(&stego, UnsafeChunks::Discard)?;
+          Ok((payload.len(), capacity, discarded))
+        })
+        .map_err(|e| format!("Failed to embed message: {}", e))?;
+
+      save(&png.as_bytes(), target, &args.write)?;
+      report_discarded(&discarded, out);
+      out.line(format!("Embedded {} of {} bytes of capacity", len, capacity));
+    },
+    Commands::Extract(args) => {
+      let options: LsbOptions = (&args.lsb).into();
+
+      let passphrase = args.passphrase.as_deref().filter(|_| args.decrypt);
+
+      let (_, png) = read_png(filepath)?;
+
+      let message = png.image()
+        .and_then(|image| image.extract_lsb(&options))
+        .and_then(|payload| message::unpack(&payload, passphrase))
+        .map_err(|e| format!("Failed to extract message: {}", e))?;
+
+      out.line(String::from_utf8_lossy(&message));
+    },
+    Commands::Attach(args) => {
+      let (_, mut png) = read_png(filepath)?;
+
+      for path in args.paths.iter() {
+        let mime_type = args.mime.as_deref().unwrap_or_else(|| attachment::guess_mime_type(path));
+
+        let content = fs::read(path).map_err(|e| format!("Failed to attach {}", e))?;
+
+        let attachment = Attachment::new(path, mime_type, content)
+          .and_then(|attachment| {
+            png.attach(&attachment, args.compress.clone().map(|c| c.into()))?;
+            Ok(attachment)
+          })
+          .map_err(|e| format!("Failed to attach {}: {}", path, e))?;
+
+        out.line(format!("Attached {} ({}, {} bytes)", attachment.filename(), attachment.mime_type(), attachment.size()));
+      }
+
+      save(&png.as_bytes(), target, &args.write)?;
+    },
+    Commands::Attachments(_) => {
+      let (_, png) = read_png(filepath)?;
+
+      let attachments = png.attachments()
+        .map_err(|e| format!("Failed to read attachments: {}", e))?;
+
+      if attachments.is_empty() {
+        out.line("No attachments");
+      }
+
+      for attachment in attachments.iter() {
+        out.line(format!(
+          "{}  {}  {} bytes  sha256:{}{}",
+          attachment.filename(),
+          attachment.mime_type(),
+          attachment.size(),
+          attachment.sha256_hex(),
+          if attachment.is_intact() { "" } else { "  (corrupted)" },
+        ));
+      }
+    },
+    Commands::Detach(args) => {
+      let names = &args.names;
+
+      let (buffer, mut png) = read_png(filepath)?;
+
+      let detached = png.detach(|attachment| names.is_empty() || names.iter().any(|name| name == attachment.filename()));
+
+      for name in names.iter().filter(|name| !detached.iter().any(|a| a.filename() == name.as_str())) {
+        out.line(format!("No attachment named {}", name));
+      }
+
+      if detached.is_empty() {
+        save_unchanged(&buffer, filepath, target)?;
+        return Ok(());
+      }
+
+      if let Some(dir) = &args.extract_to {
+        if let Some(corrupted) = detached.iter().find(|attachment| !attachment.is_intact()) {
+          return Err(format!("Failed to extract {}: the content does not match its SHA-256", corrupted.filename()))
+        }
+
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create directory {}", e))?;
+
+        for attachment in detached.iter() {
+          let path = Path::new(dir).join(attachment.filename());
+          fs::write_atomic(&path, attachment.content()).map_err(|e| format!("Failed to extract {}", e))?;
+          out.line(format!("Extracted {} ({} bytes)", path.display(), attachment.size()));
+        }
+      }
+
+      save(&png.as_bytes(), target, &args.write)?;
+
+      for attachment in detached.iter() {
+        out.line(format!("Detached {}", attachment.filename()));
+      }
+    },
   };
+
+  Ok(())
+}
+
+fn import(args: &ImportArgs) -> Result<(), String> {
+  let bytes = fs::read(&args.file).map_err(|e| format!("Failed to read {}", e))?;
+
+  let image = Image::from_netpbm(&bytes).map_err(|e| format!("Failed to import {}: {}", args.file, e))?;
+  let png = Png::from_image(&image).map_err(|e| format!("Failed to import {}: {}", args.file, e))?;
+
+  fs::write_atomic(&args.output, &png.as_bytes()).map_err(|e| format!("Failed to write {}", e))?;
+
+  if args.output != STDIO_PATH {
+    println!("Imported {} as {} ({} bit), {}x{}", args.file, image.color_type(), image.bit_depth(), image.width(), image.height());
+  }
+
+  Ok(())
+}
+
+/// Print the changes from the first PNG to the second, returning whether they are the same
+fn diff(args: &DiffArgs) -> Result<bool, String> {
+  let (_, a) = read_png(&args.a)?;
+  let (_, b) = read_png(&args.b)?;
+
+  let diff = a.diff(&b);
+
+  println!("--- {}", args.a);
+  println!("+++ {}", args.b);
+  println!("{}", diff);
+
+  Ok(diff.is_empty())
+}
+
+fn keygen(args: &KeygenArgs) -> Result<(), String> {
+  let key_file = &args.key_file;
+  let pub_file = format!("{}.pub", key_file);
+
+  if Path::new(key_file).exists() || Path::new(&pub_file).exists() {
+    return Err(format!("Failed to generate key: {} or {} already exists", key_file, pub_file))
+  }
+
+  let key = sign::generate_key();
+
+  let (private, public) = sign::signing_key_to_pem(&key)
+    .and_then(|private| Ok((private, sign::verifying_key_to_pem(&key.verifying_key())?)))
+    .map_err(|e| format!("Failed to generate key: {}", e))?;
+
+  fs::write_new(key_file, private.as_bytes(), true)
+    .and_then(|_| fs::write_new(&pub_file, public.as_bytes(), false))
+    .map_err(|e| format!("Failed to write key {}", e))?;
+
+  println!("Private key: {}", key_file);
+  println!("Public key: {}", pub_file);
+
+  Ok(())
+}
+
+/// Where the result on `filepath` is written: `--output`, a file of the same name in the `--output` directory,
+/// or `filepath` itself
+fn target_of(args: Option<&WriteArgs>, filepath: &str) -> Result<String, String> {
+  let Some(output) = args.and_then(|args| args.output.as_deref()) else {
+    return Ok(filepath.to_string())
+  };
+
+  if !Path::new(output).is_dir() {
+    return Ok(output.to_string())
+  }
+
+  let name = Path::new(filepath).file_name()
+    .filter(|_| filepath != STDIO_PATH)
+    .ok_or_else(|| format!("Failed: {} has no file name to write into {}", filepath, output))?;
+
+  Ok(Path::new(output).join(name).to_string_lossy().into_owned())
+}
+
+/// `target` with `extension` for a file written in another format, unless `--output` names the file
+fn renamed_target(target: &str, args: &WriteArgs, extension: &str) -> String {
+  match args.output.as_deref() {
+    Some(output) if !Path::new(output).is_dir() => target.to_string(),
+    _ if target == STDIO_PATH => target.to_string(),
+    _ => Path::new(target).with_extension(extension).to_string_lossy().into_owned(),
+  }
+}
+
+/// Results of several files go to distinct files of the `--output` directory
+fn check_targets(args: Option<&WriteArgs>, files: &[String]) -> Result<(), String> {
+  let Some(output) = args.and_then(|args| args.output.as_deref()) else {
+    return Ok(())
+  };
+
+  if files.len() < 2 {
+    return Ok(())
+  }
+
+  if !Path::new(output).is_dir() {
+    return Err(format!("--output must be a directory to write several files: {}", output))
+  }
+
+  let mut targets = HashSet::new();
+
+  for file in files {
+    let target = target_of(args, file)?;
+
+    if !targets.insert(target.clone()) {
+      return Err(format!("Several files would be written to {}", target))
+    }
+  }
+
+  Ok(())
+}
+
+/// Write `bytes` to `target`, keeping the file it replaces as `<target>.bak` with `--backup`
+fn save(bytes: &[u8], target: &str, args: &WriteArgs) -> Result<(), String> {
+  if args.backup && target != STDIO_PATH && Path::new(target).exists() {
+    fs::backup(target).map_err(|e| format!("Failed to back up {}", e))?;
+  }
+
+  fs::write_atomic(target, bytes).map_err(|e| format!("Failed to write {}", e))
+}
+
+/// Pass the file on unchanged when the result goes elsewhere than `filepath`
+fn save_unchanged(buffer: &[u8], filepath: &str, target: &str) -> Result<(), String> {
+  if target != filepath || target == STDIO_PATH {
+    fs::write_atomic(target, buffer).map_err(|e| format!("Failed to write {}", e))?;
+  }
+
+  Ok(())
+}
+
+/// Contents of the PNG file at `filepath`, and the PNG parsed from them
+fn read_png(filepath: &str) -> Result<(Vec<u8>, Png), String> {
+  let buffer = fs::read(filepath).map_err(|e| format!("Failed to read {}", e))?;
+
+  let png = Png::try_from(buffer.as_slice())
+    .map_err(|e| format!("Not a valid png format: {}", e))?;
+
+  Ok((buffer, png))
+}
+
+/// Color type and bit depth of the IHDR chunk
+fn format_of(png: &Png) -> String {
+  match png.header_chunk() {
+    Some(header) => format!("{} ({} bit)", header.color_type(), header.bit_depth()),
+    None => String::from("Unknown"),
+  }
+}
+
+/// Table of the chunks as read from the file, bad CRCs and invalid types included, then the share of image data and metadata
+fn chunk_table(buffer: &[u8], out: &mut Output) {
+  out.line("    #      offset  type      length  crc  summary");
+
+  let scan = check::scan(buffer);
+
+  let (mut image_data, mut image_data_count) = (0, 0);
+  let (mut metadata, mut metadata_count) = (0, 0);
+
+  for (i, chunk) in scan.chunks.iter().enumerate() {
+    let name = chunk.name();
+    let chunk_type = ChunkType::try_from(chunk.chunk_type).ok();
+    // length, type and CRC fields around the data
+    let size = chunk.data.len() + 12;
+
+    let summary = match (ChunkFields::of_data(&name, chunk.data), &chunk_type) {
+      (Some(fields), _) => fields.to_string(),
+      (None, Some(chunk_type)) if !chunk_type.is_critical() => format!(
+        "{}, {}",
+        if chunk_type.is_public() { "public" } else { "private" },
+        if chunk_type.is_safe_to_copy() { "safe to copy" } else { "unsafe to copy" },
+      ),
+      (None, Some(_)) => String::new(),
+      (None, None) => String::from("invalid chunk type"),
+    };
+
+    let line = format!(
+      "{:>5}  {:>10}  {:<4}  {:>10}  {:<3}  {}",
+      i,
+      chunk.offset,
+      name.escape_default().to_string(),
+      chunk.data.len(),
+      if chunk.crc == chunk.computed_crc() { "ok" } else { "bad" },
+      summary,
+    );
+    out.line(line.trim_end());
+
+    if chunk.is("IDAT") {
+      image_data += size;
+      image_data_count += 1;
+    } else if chunk_type.is_some_and(|chunk_type| !chunk_type.is_critical()) {
+      metadata += size;
+      metadata_count += 1;
+    }
+  }
+
+  let file_size = buffer.len();
+  let share = |bytes: usize| bytes as f64 * 100.0 / file_size.max(1) as f64;
+
+  out.line("");
+  out.line(format!("{} chunks, {} bytes", scan.chunks.len(), file_size));
+
+  if scan.end < file_size {
+    out.line(format!("{} bytes after the last chunk", file_size - scan.end));
+  }
+
+  out.line(format!("Image data: {} bytes ({:.1}%) in {} IDAT chunks", image_data, share(image_data), image_data_count));
+  out.line(format!("Metadata: {} bytes ({:.1}%) in {} ancillary chunks", metadata, share(metadata), metadata_count));
+}
+
+fn unsafe_chunks(keep_unsafe: bool) -> UnsafeChunks {
+  if keep_unsafe { UnsafeChunks::Keep } else { UnsafeChunks::Discard }
+}
+
+/// Report the chunks dropped because the image data changed
+fn report_discarded(chunks: &[Chunk], out: &mut Output) {
+  for chunk in chunks.iter() {
+    out.line(format!("Discarded chunk {} ({} bytes)", chunk.chunk_type(), chunk.length()));
+  }
 }
@@ -0,0 +1,353 @@
+// Whole files attached to a PNG, one custom chunk each
+
+use sha2::{Digest, Sha256};
+
+use crate::chunk::{Chunk, ChunkType};
+use crate::error::PngError;
+use crate::message::compress::{self, CompressionMethod};
+use crate::Png;
+
+/// Private, safe to copy: attachments do not depend on the image
+pub const ATTACHMENT_CHUNK: &str = "atCh";
+
+/// Chunk layout: version, filename (u16 length, UTF-8), MIME type (u8 length, UTF-8),
+/// size (u64), SHA-256 of the content, compression flag (u8), then the content,
+/// behind the payload header naming the method if the flag is 1
+const ATTACHMENT_VERSION: u8 = 2;
+
+/// Version 1 had no compression flag, the content was compressed if it started with the payload header
+const ATTACHMENT_VERSION_1: u8 = 1;
+
+/// Max data length of a chunk
+const MAX_CHUNK_DATA_LEN: usize = (1 << 31) - 1;
+
+const MIME_TYPES: [(&str, &str); 17] = [
+  ("txt", "text/plain"),
+  ("md", "text/markdown"),
+  ("csv", "text/csv"),
+  ("html", "text/html"),
+  ("xml", "application/xml"),
+  ("json", "application/json"),
+  ("toml", "application/toml"),
+  ("pdf", "application/pdf"),
+  ("zip", "application/zip"),
+  ("gz", "application/gzip"),
+  ("png", "image/png"),
+  ("jpg", "image/jpeg"),
+  ("jpeg", "image/jpeg"),
+  ("gif", "image/gif"),
+  ("webp", "image/webp"),
+  ("svg", "image/svg+xml"),
+  ("wasm", "application/wasm"),
+];
+
+const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+/// MIME type of `filename` by its extension
+pub fn guess_mime_type(filename: &str) -> &'static str {
+  let extension = filename.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase());
+
+  MIME_TYPES.iter()
+    .find(|(ext, _)| extension.as_deref() == Some(*ext))
+    .map_or(DEFAULT_MIME_TYPE, |(_, mime_type)| mime_type)
+}
+
+pub struct Attachment {
+  filename: String,
+  mime_type: String,
+  size: u64,
+  sha256: [u8; 32],
+  content: Vec<u8>,
+}
+
+impl Attachment {
+  /// New attachment of `content`. Directories are stripped from `filename`
+  pub fn new(filename: &str, mime_type: &str, content: Vec<u8>) -> Result<Self, PngError> {
+    let filename = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+
+    if !is_plain_filename(filename) {
+      return Err(PngError::InvalidAttachment)
+    }
+
+    if mime_type.len() > u8::MAX as usize {
+      return Err(PngError::InvalidAttachment)
+    }
+
+    Ok(Self {
+      filename: filename.to_string(),
+      mime_type: mime_type.to_string(),
+      size: content.len() as u64,
+      sha256: Sha256::digest(&content).into(),
+      content,
+    })
+  }
+
+  /// File name, without directories
+  pub fn filename(&self) -> &str {
+    &self.filename
+  }
+
+  pub fn mime_type(&self) -> &str {
+    &self.mime_type
+  }
+
+  /// Size of the content, as recorded when attached
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  pub fn sha256(&self) -> &[u8; 32] {
+    &self.sha256
+  }
+
+  pub fn sha256_hex(&self) -> String {
+    self.sha256.iter().map(|b| format!("{:02x}", b)).collect()
+  }
+
+  pub fn content(&self) -> &[u8] {
+    &self.content
+  }
+
+  /// Whether the content still matches the recorded size and hash
+  pub fn is_intact(&self) -> bool {
+    self.content.len() as u64 == self.size && Sha256::digest(&self.content)[..] == self.sha256
+  }
+
+  /// Chunk data of the attachment, with the content compressed by `compression`
+  pub fn as_bytes(&self, compression: Option<CompressionMethod>) -> Result<Vec<u8>, PngError> {
+    let content = match compression {
+      Some(method) => compress::compress(method, &self.content)?,
+      None => self.content.clone(),
+    };
+
+    let mut bytes = vec![ATTACHMENT_VERSION];
+    bytes.extend_from_slice(&(self.filename.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(self.filename.as_bytes());
+    bytes.push(self.mime_type.len() as u8);
+    bytes.extend_from_slice(self.mime_type.as_bytes());
+    bytes.extend_from_slice(&self.size.to_be_bytes());
+    bytes.extend_from_slice(&self.sha256);
+    bytes.push(compression.is_some() as u8);
+    bytes.extend(content);
+
+    if bytes.len() > MAX_CHUNK_DATA_LEN {
+      return Err(PngError::PayloadTooLarge(MAX_CHUNK_DATA_LEN))
+    }
+
+    Ok(bytes)
+  }
+}
+
+/// A file name without directories, safe to join to an extraction directory
+fn is_plain_filename(filename: &str) -> bool {
+  !filename.is_empty()
+    && filename != "."
+    && filename != ".."
+    && !filename.contains(['/', '\\', '\0'])
+    && filename.len() <= u16::MAX as usize
+}
+
+/// Split `len` bytes off the front of `rest`
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Result<&'a [u8], PngError> {
+  if rest.len() < len {
+    return Err(PngError::InvalidAttachment)
+  }
+
+  let (taken, remaining) = rest.split_at(len);
+  *rest = remaining;
+
+  Ok(taken)
+}
+
+impl TryFrom<&[u8]> for Attachment {
+  type Error = PngError;
+
+  fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+    let mut rest = bytes;
+
+    let version = take(&mut rest, 1)?[0];
+
+    if version != ATTACHMENT_VERSION && version != ATTACHMENT_VERSION_1 {
+      return Err(PngError::InvalidAttachment)
+    }
+
+    let filename_len = u16::from_be_bytes(take(&mut rest, 2)?.try_into().unwrap()) as usize;
+    let filename = String::from_utf8(take(&mut rest, filename_len)?.to_vec())?;
+
+    if !is_plain_filename(&filename) {
+      return Err(PngError::InvalidAttachment)
+    }
+
+    let mime_type_len = take(&mut rest, 1)?[0] as usize;
+    let mime_type = String::from_utf8(take(&mut rest, mime_type_len)?.to_vec())?;
+    let size = u64::from_be_bytes(take(&mut rest, 8)?.try_into().unwrap());
+    let sha256: [u8; 32] = take(&mut rest, 32)?.try_into().unwrap();
+
+    let compressed = match version {
+      ATTACHMENT_VERSION_1 => compress::is_compressed(rest),
+      _ => match take(&mut rest, 1)?[0] {
+        0 => false,
+        1 => true,
+        _ => return Err(PngError::InvalidAttachment),
+      },
+    };
+
+    let content = match compressed {
+      true => compress::decompress(rest)?,
+      false => rest.to_vec(),
+    };
+
+    Ok(Self { filename, mime_type, size, sha256, content })
+  }
+}
+
+impl Png {
+  /// Every attachment, in file order
+  pub fn attachments(&self) -> Result<Vec<Attachment>, PngError> {
+    self.get_chunks(ATTACHMENT_CHUNK)
+      .iter()
+      .map(|chunk| Attachment::try_from(&chunk.data()[..]))
+      .collect()
+  }
+
+  /// Attach `attachment` before IEND, replacing an attachment of the same filename
+  pub fn attach(&mut self, attachment: &Attachment, compression: Option<CompressionMethod>) -> Result<(), PngError> {
+    let chunk = Chunk::new(ChunkType::try_from(*b"atCh")?, attachment.as_bytes(compression)?);
+
+    if self.chunk_position("IEND").is_none() {
+      return Err(PngError::ChunkNotFoundError)
+    }
+
+    self.detach(|existing| existing.filename() == attachment.filename());
+
+    // detaching may have moved IEND
+    let end_pos = self.chunk_position("IEND").ok_or(PngError::ChunkNotFoundError)?;
+    self.insert_chunk(end_pos, chunk);
+
+    Ok(())
+  }
+
+  /// Remove the attachments matching `f`, returning them in order.
+  /// Chunks that are not valid attachments are left in place.
+  pub fn detach<F: FnMut(&Attachment) -> bool>(&mut self, mut f: F) -> Vec<Attachment> {
+    let mut detached = vec![];
+
+    self.remove_chunks_by(|chunk| {
+      if chunk.chunk_type().to_string() != ATTACHMENT_CHUNK {
+        return false
+      }
+
+      match Attachment::try_from(&chunk.data()[..]) {
+        Ok(attachment) if f(&attachment) => {
+          detached.push(attachment);
+          true
+        },
+        _ => false,
+      }
+    });
+
+    detached
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::color_type::ColorType;
+  use crate::image::Image;
+
+  fn testing_png() -> Png {
+    let image = Image::new(1, 1, ColorType::Grayscale, 8, vec![0]).unwrap();
+    Png::from_image(&image).unwrap()
+  }
+
+  #[test]
+  fn test_guess_mime_type() {
+    assert_eq!(guess_mime_type("LICENSE.PDF"), "application/pdf");
+    assert_eq!(guess_mime_type("sidecar.json"), "application/json");
+    assert_eq!(guess_mime_type("README"), DEFAULT_MIME_TYPE);
+  }
+
+  #[test]
+  fn test_attachment_round_trip() {
+    let attachment = Attachment::new("../docs/notes.txt", "text/plain", b"line\n".repeat(100)).unwrap();
+    assert_eq!(attachment.filename(), "notes.txt");
+
+    for compression in [None, Some(CompressionMethod::Zstd)] {
+      let bytes = attachment.as_bytes(compression).unwrap();
+      let parsed = Attachment::try_from(&bytes[..]).unwrap();
+
+      assert_eq!(parsed.filename(), "notes.txt");
+      assert_eq!(parsed.mime_type(), "text/plain");
+      assert_eq!(parsed.size(), 500);
+      assert_eq!(parsed.content(), attachment.content());
+      assert!(parsed.is_intact());
+    }
+
+    assert!(Attachment::new("..", "text/plain", vec![]).is_err());
+    assert!(Attachment::try_from(&b"\x01\x00"[..]).is_err());
+
+    // stored names with directories are rejected
+    let mut bytes = Attachment::new("a.txt", "text/plain", vec![]).unwrap().as_bytes(None).unwrap();
+    bytes[3] = b'/';
+    assert!(Attachment::try_from(&bytes[..]).is_err());
+  }
+
+  #[test]
+  fn test_content_looking_compressed() {
+    let content = compress::compress(CompressionMethod::Deflate, b"kept as is").unwrap();
+    assert!(compress::is_compressed(&content));
+
+    let attachment = Attachment::new("a.bin", DEFAULT_MIME_TYPE, content.clone()).unwrap();
+    let parsed = Attachment::try_from(&attachment.as_bytes(None).unwrap()[..]).unwrap();
+
+    assert_eq!(parsed.content(), content);
+    assert!(parsed.is_intact());
+
+    // version 1 chunks have no flag
+    let mut bytes = Attachment::new("a.txt", "text/plain", b"old".to_vec()).unwrap().as_bytes(None).unwrap();
+    bytes[0] = ATTACHMENT_VERSION_1;
+    let flag = bytes.len() - 4;
+    bytes.remove(flag);
+
+    assert_eq!(Attachment::try_from(&bytes[..]).unwrap().content(), b"old");
+  }
+
+  #[test]
+  fn test_tampered_content_is_detected() {
+    let attachment = Attachment::new("a.bin", DEFAULT_MIME_TYPE, vec![1, 2, 3]).unwrap();
+    let mut bytes = attachment.as_bytes(None).unwrap();
+    *bytes.last_mut().unwrap() = 9;
+
+    assert!(!Attachment::try_from(&bytes[..]).unwrap().is_intact());
+  }
+
+  #[test]
+  fn test_attach_and_detach() {
+    let mut png = testing_png();
+
+    png.attach(&Attachment::new("a.json", "application/json", b"{}".to_vec()).unwrap(), None).unwrap();
+    png.attach(&Attachment::new("b.pdf", "application/pdf", b"%PDF".to_vec()).unwrap(), None).unwrap();
+    png.attach(&Attachment::new("a.json", "application/json", b"[]".to_vec()).unwrap(), None).unwrap();
+
+    let attachments = png.attachments().unwrap();
+    let names: Vec<&str> = attachments.iter().map(|a| a.filename()).collect();
+    assert_eq!(names, vec!["b.pdf", "a.json"]);
+    assert_eq!(attachments[1].content(), b"[]");
+    assert_eq!(png.chunks().last().unwrap().chunk_type().to_string(), "IEND");
+
+    let detached = png.detach(|a| a.filename() == "b.pdf");
+    assert_eq!(detached.len(), 1);
+    assert_eq!(png.attachments().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn test_attach_without_end() {
+    let mut png = testing_png();
+    png.attach(&Attachment::new("a.json", "application/json", b"{}".to_vec()).unwrap(), None).unwrap();
+    png.remove_chunk("IEND").unwrap();
+
+    assert!(png.attach(&Attachment::new("a.json", "application/json", b"[]".to_vec()).unwrap(), None).is_err());
+    assert_eq!(png.attachments().unwrap()[0].content(), b"{}");
+  }
+}
@@ -0,0 +1,558 @@
+// Validation of PNG files against the spec, reporting every problem instead of stopping at the first
+
+use std::fmt;
+use std::fmt::Display;
+use std::io::Read;
+use flate2::bufread::DeflateDecoder;
+
+use crate::chunk::{crc_of, CHUNK_CRC_BYTE_LEN, CHUNK_LENGTH_BYTE_LEN, CHUNK_TYPE_BYTE_LEN};
+use crate::chunk::image_header::{ChunkImageHeader, IMAGE_HEADER_CHUNK_DATA_LEN};
+use crate::codec;
+use crate::color_type::ColorType;
+use crate::filter_type::FilterType;
+use crate::Png;
+
+/// Critical chunk types known to this version of PNG
+pub(crate) const CRITICAL_CHUNKS: [&str; 4] = ["IHDR", "PLTE", "IDAT", "IEND"];
+
+/// Ancillary chunks that must come before PLTE and IDAT
+pub(crate) const BEFORE_PALETTE: [&str; 5] = ["cHRM", "gAMA", "iCCP", "sBIT", "sRGB"];
+
+/// Ancillary chunks that must come after PLTE, if any, and before IDAT
+pub(crate) const AFTER_PALETTE: [&str; 3] = ["bKGD", "hIST", "tRNS"];
+
+/// Ancillary chunks that must come before IDAT
+pub(crate) const BEFORE_IMAGE_DATA: [&str; 2] = ["pHYs", "sPLT"];
+
+/// Ancillary chunks that may appear at most once
+const SINGLE_CHUNKS: [&str; 12] = ["cHRM", "gAMA", "iCCP", "sBIT", "sRGB", "bKGD", "hIST", "tRNS", "pHYs", "tIME", "eXIf", "PLTE"];
+
+/// Zlib compression method for deflate
+const ZLIB_DEFLATE: u8 = 8;
+
+/// Zlib flag of a preset dictionary, which PNG does not allow
+const ZLIB_PRESET_DICTIONARY: u8 = 0x20;
+
+const ADLER_MODULUS: u32 = 65521;
+
+pub struct Problem {
+  /// Offset in the file of the chunk or data the problem is in
+  pub offset: Option<usize>,
+  pub message: String,
+}
+
+impl Display for Problem {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+    match self.offset {
+      Some(offset) => write!(f, "offset {}: {}", offset, self.message),
+      None => write!(f, "{}", self.message),
+    }
+  }
+}
+
+/// Chunk as laid out in the file, read without any validation
+pub struct RawChunk<'a> {
+  /// Offset of the chunk length field from the start of the file
+  pub offset: usize,
+  pub chunk_type: [u8; CHUNK_TYPE_BYTE_LEN],
+  pub data: &'a [u8],
+  pub crc: u32,
+}
+
+impl RawChunk<'_> {
+  pub fn name(&self) -> String {
+    self.chunk_type.iter().map(|&b| b as char).collect()
+  }
+
+  pub fn is(&self, chunk_type: &str) -> bool {
+    self.chunk_type == chunk_type.as_bytes()
+  }
+
+  pub fn computed_crc(&self) -> u32 {
+    crc_of(&self.chunk_type, self.data)
+  }
+}
+
+/// Chunks of a file, read up to a chunk running past the end of the file.
+/// After IEND, reading also stops at data that does not start with a valid chunk type.
+pub struct Scan<'a> {
+  pub chunks: Vec<RawChunk<'a>>,
+  /// Offset where the chunks stop
+  pub end: usize,
+}
+
+/// Read the chunks after the signature, trusting only their length fields
+pub fn scan(bytes: &[u8]) -> Scan<'_> {
+  let mut chunks = vec![];
+  let mut offset = Png::SIGNATURE.len();
+
+  let mut after_end = false;
+
+  while offset < bytes.len() {
+    let Some(length) = bytes.get(offset..offset + CHUNK_LENGTH_BYTE_LEN) else { break };
+    let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+
+    let type_start = offset + CHUNK_LENGTH_BYTE_LEN;
+    let data_start = type_start + CHUNK_TYPE_BYTE_LEN;
+    let crc_start = data_start.saturating_add(length);
+
+    let Some(crc) = bytes.get(crc_start..crc_start.saturating_add(CHUNK_CRC_BYTE_LEN)) else { break };
+    let chunk_type: [u8; CHUNK_TYPE_BYTE_LEN] = bytes[type_start..data_start].try_into().unwrap();
+
+    // trailing data rather than chunks
+    if after_end && !chunk_type.iter().all(u8::is_ascii_alphabetic) {
+      break
+    }
+
+    after_end |= &chunk_type == b"IEND";
+
+    chunks.push(RawChunk {
+      offset,
+      chunk_type,
+      data: &bytes[data_start..crc_start],
+      crc: u32::from_be_bytes(crc.try_into().unwrap()),
+    });
+
+    offset = crc_start + CHUNK_CRC_BYTE_LEN;
+  }
+
+  Scan { chunks, end: offset }
+}
+
+pub(crate) fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+
+  // 5552 bytes is the most that can be summed before b overflows
+  for block in data.chunks(5552) {
+    for &byte in block {
+      a += byte as u32;
+      b += a;
+    }
+
+    a %= ADLER_MODULUS;
+    b %= ADLER_MODULUS;
+  }
+
+  (b << 16) | a
+}
+
+/// Problems with the two byte header of a zlib stream
+pub(crate) fn zlib_header_problems(compressed: &[u8]) -> Vec<String> {
+  let [cmf, flg, ..] = compressed else {
+    return vec![String::from("zlib stream is too short for its header")]
+  };
+
+  let mut problems = vec![];
+
+  if cmf & 0x0f != ZLIB_DEFLATE {
+    problems.push(format!("zlib compression method is {}, not deflate", cmf & 0x0f));
+  } else if cmf >> 4 > 7 {
+    problems.push(format!("zlib window size exponent is {}, more than 7", cmf >> 4));
+  }
+
+  if !(*cmf as u16 * 256 + *flg as u16).is_multiple_of(31) {
+    problems.push(String::from("zlib header check bits are wrong"));
+  }
+
+  if flg & ZLIB_PRESET_DICTIONARY != 0 {
+    problems.push(String::from("zlib stream uses a preset dictionary"));
+  }
+
+  problems
+}
+
+/// Inflate raw deflate data up to `limit` bytes, returning what could be inflated, the number of bytes consumed,
+/// and the error that stopped the inflation, if any
+pub(crate) fn inflate_raw(deflated: &[u8], limit: u64) -> (Vec<u8>, usize, Option<String>) {
+  let mut decoder = DeflateDecoder::new(deflated);
+  let mut data = vec![];
+
+  let error = (&mut decoder).take(limit).read_to_end(&mut data).err().map(|e| e.to_string());
+
+  (data, decoder.total_in() as usize, error)
+}
+
+/// Inflate a zlib stream up to `limit` bytes, checking it on the way. Returns what could be inflated and the problems found.
+/// The checksum is not checked when inflation stops at the limit.
+fn inflate_checked(compressed: &[u8], limit: u64) -> (Vec<u8>, Vec<String>) {
+  let mut problems = zlib_header_problems(compressed);
+
+  let deflated = compressed.get(2..).unwrap_or_default();
+  let (data, consumed, error) = inflate_raw(deflated, limit);
+
+  if let Some(e) = error {
+    problems.push(format!("deflate stream is corrupt after {} decompressed bytes: {}", data.len(), e));
+    return (data, problems)
+  }
+
+  if data.len() as u64 == limit {
+    return (data, problems)
+  }
+
+  let trailer = &deflated[consumed..];
+
+  match trailer.get(..4) {
+    None => problems.push(String::from("zlib stream ends without its Adler-32 checksum")),
+    Some(checksum) => {
+      let expected = u32::from_be_bytes(checksum.try_into().unwrap());
+      let computed = adler32(&data);
+
+      if expected != computed {
+        problems.push(format!("Adler-32 checksum is {:08x}, the data sums to {:08x}", expected, computed));
+      }
+    },
+  }
+
+  if trailer.len() > 4 {
+    problems.push(format!("{} bytes follow the end of the zlib stream", trailer.len() - 4));
+  }
+
+  (data, problems)
+}
+
+/// Width and height are between 1 and 2^31 - 1
+fn is_valid_dimension(value: u32) -> bool {
+  value != 0 && value <= i32::MAX as u32
+}
+
+struct Checker {
+  problems: Vec<Problem>,
+}
+
+impl Checker {
+  fn report(&mut self, offset: Option<usize>, message: String) {
+    self.problems.push(Problem { offset, message });
+  }
+
+  fn chunks(&mut self, chunks: &[RawChunk]) {
+    for chunk in chunks.iter() {
+      let name = chunk.name();
+
+      if !chunk.chunk_type.iter().all(|b| b.is_ascii_alphabetic()) {
+        self.report(Some(chunk.offset), format!("chunk type {:?} is not made of letters", name));
+        continue;
+      }
+
+      if chunk.chunk_type[2].is_ascii_lowercase() {
+        self.report(Some(chunk.offset), format!("{} has the reserved bit set", name));
+      }
+
+      if chunk.chunk_type[0].is_ascii_uppercase() && !CRITICAL_CHUNKS.contains(&name.as_str()) {
+        self.report(Some(chunk.offset), format!("{} is an unknown critical chunk", name));
+      }
+
+      if chunk.crc != chunk.computed_crc() {
+        self.report(Some(chunk.offset), format!("{} CRC is {:08x}, the chunk sums to {:08x}", name, chunk.crc, chunk.computed_crc()));
+      }
+    }
+  }
+
+  fn header(&mut self, chunks: &[RawChunk]) -> Option<ChunkImageHeader> {
+    let chunk = chunks.iter().find(|chunk| chunk.is("IHDR"))?;
+
+    let Ok(bytes) = <[u8; IMAGE_HEADER_CHUNK_DATA_LEN]>::try_from(chunk.data) else {
+      self.report(Some(chunk.offset), format!("IHDR is {} bytes long, not {}", chunk.data.len(), IMAGE_HEADER_CHUNK_DATA_LEN));
+      return None
+    };
+
+    let header = match ChunkImageHeader::try_from(bytes) {
+      Ok(header) => header,
+      Err(e) => {
+        self.report(Some(chunk.offset), format!("IHDR: {}", e));
+        return None
+      },
+    };
+
+    for (dimension, value) in [("width", header.width()), ("height", header.height())] {
+      if !is_valid_dimension(value) {
+        self.report(Some(chunk.offset), format!("IHDR: {} is {}, not between 1 and 2^31 - 1", dimension, value));
+      }
+    }
+
+    Some(header)
+  }
+
+  /// Rules of the spec on the order and number of chunks
+  fn order(&mut self, chunks: &[RawChunk], header: Option<&ChunkImageHeader>) {
+    let first = |chunk_type: &str| chunks.iter().position(|chunk| chunk.is(chunk_type));
+    let count = |chunk_type: &str| chunks.iter().filter(|chunk| chunk.is(chunk_type)).count();
+
+    match chunks.first() {
+      None => return self.report(None, String::from("there are no chunks")),
+      Some(chunk) if !chunk.is("IHDR") => self.report(Some(chunk.offset), format!("the first chunk is {}, not IHDR", chunk.name())),
+      _ => {},
+    }
+
+    for chunk in chunks.iter().skip(1).filter(|chunk| chunk.is("IHDR")) {
+      self.report(Some(chunk.offset), String::from("IHDR appears more than once"));
+    }
+
+    match chunks.iter().position(|chunk| chunk.is("IEND")) {
+      None => self.report(None, String::from("IEND is missing")),
+      Some(i) if i + 1 != chunks.len() => self.report(Some(chunks[i + 1].offset), format!("{} chunks follow IEND", chunks.len() - i - 1)),
+      _ => {},
+    }
+
+    let image_data = first("IDAT");
+    let palette = first("PLTE");
+
+    match image_data {
+      None => self.report(None, String::from("there is no IDAT chunk")),
+      Some(start) => {
+        let run = chunks[start..].iter().take_while(|chunk| chunk.is("IDAT")).count();
+
+        if let Some(chunk) = chunks[start + run..].iter().find(|chunk| chunk.is("IDAT")) {
+          self.report(Some(chunk.offset), String::from("IDAT chunks are not consecutive"));
+        }
+      },
+    }
+
+    for chunk_type in SINGLE_CHUNKS {
+      if count(chunk_type) > 1 {
+        let chunk = chunks.iter().filter(|chunk| chunk.is(chunk_type)).nth(1).unwrap();
+        self.report(Some(chunk.offset), format!("{} appears more than once", chunk_type));
+      }
+    }
+
+    if count("iCCP") > 0 && count("sRGB") > 0 {
+      self.report(first("sRGB").map(|i| chunks[i].offset), String::from("iCCP and sRGB are both present"));
+    }
+
+    let must_precede = |checker: &mut Self, chunk_types: &[&str], before: Option<usize>, name: &str| {
+      let Some(before) = before else { return };
+
+      for chunk in chunks[before..].iter().filter(|chunk| chunk_types.iter().any(|t| chunk.is(t))) {
+        checker.report(Some(chunk.offset), format!("{} must come before {}", chunk.name(), name));
+      }
+    };
+
+    must_precede(self, &["PLTE"], image_data, "IDAT");
+    must_precede(self, &BEFORE_PALETTE, palette, "PLTE");
+    must_precede(self, &BEFORE_PALETTE, image_data, "IDAT");
+    must_precede(self, &AFTER_PALETTE, image_data, "IDAT");
+    must_precede(self, &BEFORE_IMAGE_DATA, image_data, "IDAT");
+
+    if let Some(palette) = palette {
+      for chunk in chunks[..palette].iter().filter(|chunk| AFTER_PALETTE.iter().any(|t| chunk.is(t))) {
+        self.report(Some(chunk.offset), format!("{} must come after PLTE", chunk.name()));
+      }
+    }
+
+    if palette.is_none() && count("hIST") > 0 {
+      self.report(first("hIST").map(|i| chunks[i].offset), String::from("hIST requires a PLTE chunk"));
+    }
+
+    let Some(header) = header else { return };
+
+    match (header.color_type(), palette) {
+      (ColorType::PaletteIndex, None) => self.report(None, String::from("PLTE is required for color type 3")),
+      (ColorType::Grayscale | ColorType::GrayscaleWithAlpha, Some(i)) => {
+        self.report(Some(chunks[i].offset), format!("PLTE is not allowed for color type {}", header.color_type()));
+      },
+      _ => {},
+    }
+
+    if let Some(i) = palette {
+      let entries = chunks[i].data.len() / 3;
+
+      if !chunks[i].data.len().is_multiple_of(3) || entries == 0 || entries > 256 {
+        self.report(Some(chunks[i].offset), format!("PLTE is {} bytes long, not 3 bytes for each of 1 to 256 entries", chunks[i].data.len()));
+      }
+    }
+
+    if let (ColorType::GrayscaleWithAlpha | ColorType::RgbWithAlpha, Some(i)) = (header.color_type(), first("tRNS")) {
+      self.report(Some(chunks[i].offset), format!("tRNS is not allowed for color type {}", header.color_type()));
+    }
+  }
+
+  /// Zlib stream of the IDAT chunks, its size and the scanline filter types
+  fn image_data(&mut self, chunks: &[RawChunk], header: &ChunkImageHeader) {
+    let Some(offset) = chunks.iter().find(|chunk| chunk.is("IDAT")).map(|chunk| chunk.offset) else { return };
+
+    let compressed: Vec<u8> = chunks.iter()
+      .filter(|chunk| chunk.is("IDAT"))
+      .flat_map(|chunk| chunk.data.iter().copied())
+      .collect();
+
+    // a stream inflating past what IHDR implies is cut short
+    let expected = codec::image_data_len(header);
+    let (data, problems) = inflate_checked(&compressed, expected.saturating_add(1));
+
+    for problem in problems {
+      self.report(Some(offset), format!("IDAT: {}", problem));
+    }
+
+    match data.len() as u64 {
+      len if len > expected => self.report(Some(offset), format!("IDAT: image data decompresses to more than the {} bytes IHDR implies", expected)),
+      len if len < expected => self.report(Some(offset), format!("IDAT: image data is {} bytes decompressed, IHDR implies {}", len, expected)),
+      _ => {},
+    }
+
+    let mut at = 0;
+    let mut invalid = vec![];
+
+    for (row, len) in codec::scanline_lengths(header).enumerate() {
+      let Some(&filter_type) = data.get(at) else { break };
+
+      if FilterType::try_from(filter_type).is_err() {
+        invalid.push((row, filter_type));
+      }
+
+      at += 1 + len;
+    }
+
+    if let Some((row, filter_type)) = invalid.first() {
+      self.report(Some(offset), format!(
+        "IDAT: {} scanlines have an invalid filter type, the first is scanline {} with {}",
+        invalid.len(), row, filter_type,
+      ));
+    }
+  }
+}
+
+/// Every problem found in the encoded PNG `bytes`: chunk CRCs, chunk order, the zlib stream of the image data,
+/// its size and scanline filters, and bytes after IEND
+pub fn check(bytes: &[u8]) -> Vec<Problem> {
+  let mut checker = Checker { problems: vec![] };
+
+  if !bytes.starts_with(&Png::SIGNATURE) {
+    checker.report(Some(0), String::from("the PNG signature is missing"));
+    return checker.problems
+  }
+
+  let scan = scan(bytes);
+
+  checker.chunks(&scan.chunks);
+
+  if scan.end < bytes.len() {
+    match scan.chunks.iter().any(|chunk| chunk.is("IEND")) {
+      true => checker.report(Some(scan.end), format!("{} bytes follow IEND", bytes.len() - scan.end)),
+      false => checker.report(Some(scan.end), format!("chunk runs past the end of the file, {} bytes are left", bytes.len() - scan.end)),
+    }
+  }
+
+  let header = checker.header(&scan.chunks);
+
+  checker.order(&scan.chunks, header.as_ref());
+
+  // the size of the image data is only checked for dimensions a decoder could allocate
+  if let Some(header) = header.filter(|header| is_valid_dimension(header.width()) && is_valid_dimension(header.height())) {
+    checker.image_data(&scan.chunks, &header);
+  }
+
+  checker.problems
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::Chunk;
+  use crate::image::Image;
+
+  fn testing_png() -> Png {
+    let image = Image::new(4, 3, ColorType::Rgb, 8, (0..36).collect()).unwrap();
+    Png::from_image(&image).unwrap()
+  }
+
+  fn messages(bytes: &[u8]) -> Vec<String> {
+    check(bytes).into_iter().map(|problem| problem.message).collect()
+  }
+
+  fn with_image_data(compressed: &[u8]) -> Vec<u8> {
+    let mut png = testing_png();
+    png.replace_chunks("IDAT", vec![Chunk::new("IDAT".parse().unwrap(), compressed.to_vec())]).unwrap();
+    png.as_bytes()
+  }
+
+  #[test]
+  fn test_valid() {
+    assert!(check(&testing_png().as_bytes()).is_empty());
+  }
+
+  #[test]
+  fn test_every_problem_is_reported() {
+    let mut bytes = testing_png().as_bytes();
+    // IHDR crc
+    bytes[8 + 8 + 13] ^= 1;
+    bytes.extend_from_slice(b"junk");
+
+    let messages = messages(&bytes);
+
+    assert_eq!(messages.len(), 2);
+    assert!(messages[0].starts_with("IHDR CRC is"));
+    assert_eq!(messages[1], "4 bytes follow IEND");
+  }
+
+  #[test]
+  fn test_image_data() {
+    let compressed = testing_png().compressed_data();
+
+    let mut bad_checksum = compressed.clone();
+    *bad_checksum.last_mut().unwrap() ^= 1;
+    assert!(messages(&with_image_data(&bad_checksum))[0].starts_with("IDAT: Adler-32 checksum is"));
+
+    let mut filtered = codec::inflate(&compressed).unwrap();
+    filtered[0] = 9;
+    filtered.truncate(filtered.len() - 1);
+    let compressed = codec::deflate(&filtered, flate2::Compression::default()).unwrap();
+
+    assert_eq!(messages(&with_image_data(&compressed)), vec![
+      "IDAT: image data is 38 bytes decompressed, IHDR implies 39",
+      "IDAT: 1 scanlines have an invalid filter type, the first is scanline 0 with 9",
+    ]);
+  }
+
+  #[test]
+  fn test_image_data_is_bounded() {
+    let data = codec::inflate(&testing_png().compressed_data()).unwrap();
+    let longer: Vec<u8> = data.iter().copied().chain([0; 10]).collect();
+    let compressed = codec::deflate(&longer, flate2::Compression::default()).unwrap();
+
+    assert_eq!(messages(&with_image_data(&compressed)), vec!["IDAT: image data decompresses to more than the 39 bytes IHDR implies"]);
+
+    // an out of range height leaves the image data unchecked
+    let mut bytes = testing_png().as_bytes();
+    bytes[8 + 8 + 4..8 + 8 + 8].copy_from_slice(&u32::MAX.to_be_bytes());
+    let crc = crc_of(b"IHDR", &bytes[8 + 8..8 + 8 + 13]);
+    bytes[8 + 8 + 13..8 + 8 + 17].copy_from_slice(&crc.to_be_bytes());
+
+    assert_eq!(messages(&bytes), vec!["IHDR: height is 4294967295, not between 1 and 2^31 - 1"]);
+  }
+
+  #[test]
+  fn test_order() {
+    let mut png = testing_png();
+    png.insert_chunk(2, Chunk::new("gAMA".parse().unwrap(), vec![0, 0, 0xb1, 0x8f]));
+    png.insert_chunk(3, Chunk::new("IDAT".parse().unwrap(), vec![]));
+    png.remove_chunks_by(|chunk| chunk.chunk_type().to_string() == "IEND");
+
+    assert_eq!(messages(&png.as_bytes()), vec![
+      "IEND is missing",
+      "IDAT chunks are not consecutive",
+      "gAMA must come before IDAT",
+    ]);
+  }
+
+  #[test]
+  fn test_chunks_after_end() {
+    let mut png = testing_png();
+    png.append_chunk(Chunk::new("IDAT".parse().unwrap(), vec![]));
+    png.append_chunk(Chunk::new("tEXt".parse().unwrap(), b"Title\0late".to_vec()));
+
+    let mut bytes = png.as_bytes();
+    bytes.extend_from_slice(b"junk");
+
+    assert_eq!(scan(&bytes).chunks.len(), 5);
+    assert_eq!(messages(&bytes), vec![
+      "4 bytes follow IEND",
+      "2 chunks follow IEND",
+      "IDAT chunks are not consecutive",
+    ]);
+  }
+
+  #[test]
+  fn test_adler32() {
+    assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    assert_eq!(adler32(&vec![0xff; 100_000]), 0x149a302c);
+  }
+}
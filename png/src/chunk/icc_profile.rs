@@ -35,9 +35,13 @@ impl TryFrom<&Vec<u8>> for ChunkICCProfile {
       return Err("Profile name out of range")
     }
 
-    let profile_name = String::from_utf8(bytes[0..end].to_vec()).unwrap();
+    let Ok(profile_name) = String::from_utf8(bytes[0..end].to_vec()) else {
+      return Err("Profile name is not valid UTF-8")
+    };
 
-    let compression_method: u8 = *(bytes.get(end + 1).unwrap());
+    let Some(&compression_method) = bytes.get(end + 1) else {
+      return Err("Missing compression method")
+    };
 
     let compression_profile: Vec<u8> = bytes.iter().skip(end + 1).map(|&v| v).collect();
 
@@ -61,16 +61,7 @@ impl TryFrom<[u8; IMAGE_HEADER_CHUNK_DATA_LEN]> for ChunkImageHeader {
 
     let color_type = ColorType::try_from(*color_type_raw)?;
 
-    let group = (*color_type_raw, *bit_depth);
-
-    let valid_bit_depth = match group {
-      (0, 1 | 2 | 4 | 8 | 16) => true,
-      (2, 8 | 16) => true,
-      (3, 1 | 2 | 4 | 8) => true,
-      (4, 8 | 16) => true,
-      (6, 8 | 16) => true,
-      _ => false,
-    };
+    let valid_bit_depth = color_type.allows_bit_depth(*bit_depth);
 
     if !valid_bit_depth {
       return Err(PngError::InvalidBitDepth)
@@ -127,6 +118,27 @@ impl ChunkRawBytes for ChunkImageHeader {
 }
 
 impl ChunkImageHeader {
+  /// New header with deflate compression and the adaptive filter method
+  pub fn new(width: u32, height: u32, bit_depth: u8, color_type: ColorType, interlace_method: u8) -> Result<Self, PngError> {
+    if !color_type.allows_bit_depth(bit_depth) {
+      return Err(PngError::InvalidBitDepth)
+    }
+
+    if interlace_method > 1 {
+      return Err(PngError::InvalidInterlaceMethod)
+    }
+
+    Ok(Self {
+      width,
+      height,
+      bit_depth,
+      color_type,
+      compression_method: 0,
+      filter_method: 0,
+      interlace_method,
+    })
+  }
+
   pub fn width(&self) -> u32 {
     self.width
   }
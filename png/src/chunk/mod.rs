@@ -26,6 +26,14 @@ use super::{ColorType, PngError};
 
 const CRC_CKSUM: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
 
+/// CRC of a chunk, computed over its type and data
+pub(crate) fn crc_of(chunk_type: &[u8], data: &[u8]) -> u32 {
+  let mut digest = CRC_CKSUM.digest();
+  digest.update(chunk_type);
+  digest.update(data);
+  digest.finalize()
+}
+
 pub const CHUNK_LENGTH_BYTE_LEN: usize = 4;
 pub const CHUNK_TYPE_BYTE_LEN: usize = 4;
 pub const CHUNK_CRC_BYTE_LEN: usize = 4;
@@ -101,6 +109,8 @@ pub struct Chunk {
   chunk_type: ChunkType,
   data: ChunkData,
   crc: u32,
+  /// Offset in the file the chunk was read from
+  offset: Option<usize>,
 }
 
 fn map_chunk_data(chunk_type: &ChunkType, data: Vec<u8>) -> ChunkData {
@@ -113,15 +123,26 @@ fn map_chunk_data(chunk_type: &ChunkType, data: Vec<u8>) -> ChunkData {
       let header = ChunkImageHeader::try_from(bytes).unwrap();
       ChunkData::ImageHeader(header)
     },
+    "PLTE" => {
+      match ChunkPalette::try_from(&data[..]) {
+        Ok(palette) => ChunkData::Palette(palette),
+        Err(_) => ChunkData::Other(data),
+      }
+    },
     "IDAT" => ChunkData::ImageData(data),
+    "tRNS" => ChunkData::Transparency(ChunkTransparency::new(data)),
     "iCCP" => {
-      let icc_profile = ChunkICCProfile::try_from(&data).unwrap();
-      ChunkData::ICCProfile(icc_profile)
+      match ChunkICCProfile::try_from(&data) {
+        Ok(icc_profile) => ChunkData::ICCProfile(icc_profile),
+        Err(_) => ChunkData::Other(data),
+      }
     },
     "IEND" => ChunkData::ImageEnd,
     "tEXt" => {
-      let text = ChunkTextual::try_from(&data[..]).unwrap();
-      ChunkData::Textual(text)
+      match ChunkTextual::try_from(&data[..]) {
+        Ok(text) => ChunkData::Textual(text),
+        Err(_) => ChunkData::Other(data),
+      }
     },
     // todo: chunk compressed
     // todo: chunk international
@@ -190,6 +211,7 @@ impl TryFrom<&[u8]> for Chunk {
       chunk_type,
       data: chunk_data,
       crc,
+      offset: None,
     })
   }
 }
@@ -217,6 +239,7 @@ impl Chunk {
       chunk_type,
       data: map_chunk_data(&chunk_type, data),
       crc,
+      offset: None,
     }
   }
 
@@ -240,6 +263,15 @@ impl Chunk {
     self.crc
   }
 
+  /// Offset of the chunk in the file it was read from, `None` for chunks created since
+  pub fn offset(&self) -> Option<usize> {
+    self.offset
+  }
+
+  pub(crate) fn set_offset(&mut self, offset: usize) {
+    self.offset = Some(offset);
+  }
+
   pub fn set_data(&mut self, data: &[u8]) {
     let new_len = data.len();
     let chunk_type = self.chunk_type;
@@ -1,12 +1,17 @@
 use std::fmt;
 use std::fmt::Display;
 use crate::ChunkRawBytes;
+use crate::error::PngError;
 
 /// Palette(Red, Green, Blue)
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub struct Palette(u8, u8, u8);
 
 impl Palette {
+  pub fn new(red: u8, green: u8, blue: u8) -> Self {
+    Self(red, green, blue)
+  }
+
   pub fn red(&self) -> u8 {
     self.0
   }
@@ -26,10 +31,29 @@ impl Display for Palette {
   }
 }
 
+/// Chunk: PLTE
+/// 1 - 256 palette entries, 3 bytes each
+#[derive(Clone, PartialEq)]
 pub struct ChunkPalette {
   palettes: Vec<Palette>,
 }
 
+impl TryFrom<&[u8]> for ChunkPalette {
+  type Error = PngError;
+
+  fn try_from(bytes: &[u8]) -> Result<Self, <Self as TryFrom<&[u8]>>::Error> {
+    if bytes.is_empty() || !bytes.len().is_multiple_of(3) || bytes.len() / 3 > 256 {
+      return Err(PngError::ChunkParseError)
+    }
+
+    let palettes = bytes.chunks(3)
+      .map(|p| Palette(p[0], p[1], p[2]))
+      .collect();
+
+    Ok(Self { palettes })
+  }
+}
+
 impl Display for ChunkPalette {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
     write!(f, "{}", self.palettes.iter().map(|&p| p.to_string()).collect::<Vec<String>>().join("\n"))
@@ -46,7 +70,23 @@ impl ChunkRawBytes for ChunkPalette {
 }
 
 impl ChunkPalette {
+  pub fn new(palettes: Vec<Palette>) -> Self {
+    Self { palettes }
+  }
+
   pub fn get(&self, at: usize) -> Option<&Palette> {
     self.palettes.get(at)
   }
+
+  pub fn palettes(&self) -> &[Palette] {
+    &self.palettes
+  }
+
+  pub fn len(&self) -> usize {
+    self.palettes.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.palettes.is_empty()
+  }
 }
@@ -1,5 +1,10 @@
 use crate::{ChunkRawBytes, color_type::ColorType};
 
+/// Chunk: tRNS
+/// Palette images: one alpha byte per palette entry (may be shorter than PLTE) <br/>
+/// Grayscale: a single 2 bytes gray sample <br/>
+/// RGB: 2 bytes each for red, green and blue
+#[derive(Clone, PartialEq)]
 pub struct ChunkTransparency(Vec<u8>);
 
 impl ChunkRawBytes for ChunkTransparency {
@@ -9,10 +14,29 @@ impl ChunkRawBytes for ChunkTransparency {
 }
 
 impl ChunkTransparency {
+  pub fn new(bytes: Vec<u8>) -> Self {
+    Self(bytes)
+  }
+
   pub fn get_transparency(&self, color_type: &ColorType, at: usize) -> Option<&u8> {
     match color_type {
       ColorType::PaletteIndex => self.0.get(at),
       _ => None,
     }
   }
+
+  /// The transparent color key of grayscale (1 sample) and RGB (3 samples) images
+  pub fn color_key(&self, color_type: &ColorType) -> Option<Vec<u16>> {
+    let samples = match color_type {
+      ColorType::Grayscale => 1,
+      ColorType::Rgb => 3,
+      _ => return None,
+    };
+
+    if self.0.len() != samples * 2 {
+      return None
+    }
+
+    Some(self.0.chunks(2).map(|v| u16::from_be_bytes([v[0], v[1]])).collect())
+  }
 }
@@ -0,0 +1,272 @@
+// IDAT stream decoding and encoding
+
+use std::io::{Read, Write};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use crate::chunk::image_header::ChunkImageHeader;
+use crate::color_type::ColorType;
+use crate::error::PngError;
+use crate::filter_method::{apply_filter, reverse_filter};
+use crate::filter_type::{FilterStrategy, FilterType};
+use crate::image::Image;
+
+/// Adam7 passes: (x start, y start, x step, y step)
+const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+  (0, 0, 8, 8),
+  (4, 0, 8, 8),
+  (0, 4, 4, 8),
+  (2, 0, 4, 4),
+  (0, 2, 2, 4),
+  (1, 0, 2, 2),
+  (0, 1, 1, 2),
+];
+
+const FILTER_TYPES: [FilterType; 5] = [
+  FilterType::None,
+  FilterType::Sub,
+  FilterType::Up,
+  FilterType::Average,
+  FilterType::Paeth,
+];
+
+/// Bytes per complete pixel used by the filters, rounded up to 1
+fn filter_bpp(channels: usize, bit_depth: u8) -> usize {
+  usize::max(1, channels * bit_depth as usize / 8)
+}
+
+fn row_bytes(pixels: usize, channels: usize, bit_depth: u8) -> usize {
+  (pixels * channels * bit_depth as usize).div_ceil(8)
+}
+
+/// Split packed scanline bytes into one byte per sample (two for 16 bit depth)
+fn unpack_row(scanline: &[u8], sample_count: usize, bit_depth: u8) -> Vec<u8> {
+  match bit_depth {
+    1 | 2 | 4 => {
+      let depth = bit_depth as usize;
+      let mask = (1u8 << bit_depth) - 1;
+
+      (0..sample_count)
+        .map(|i| {
+          let bit = i * depth;
+          (scanline[bit / 8] >> (8 - depth - bit % 8)) & mask
+        })
+        .collect()
+    },
+    _ => scanline.to_vec(),
+  }
+}
+
+/// Pack samples into scanline bytes, the reverse of `unpack_row`
+fn pack_row(samples: &[u8], bit_depth: u8) -> Vec<u8> {
+  match bit_depth {
+    1 | 2 | 4 => {
+      let depth = bit_depth as usize;
+      let mut packed = vec![0; (samples.len() * depth).div_ceil(8)];
+
+      for (i, &v) in samples.iter().enumerate() {
+        let bit = i * depth;
+        packed[bit / 8] |= v << (8 - depth - bit % 8);
+      }
+
+      packed
+    },
+    _ => samples.to_vec(),
+  }
+}
+
+pub(crate) fn inflate(compressed: &[u8]) -> Result<Vec<u8>, PngError> {
+  let mut decoder = ZlibDecoder::new(compressed);
+  let mut buffer: Vec<u8> = vec![];
+
+  decoder.read_to_end(&mut buffer)?;
+
+  Ok(buffer)
+}
+
+pub(crate) fn deflate(data: &[u8], compression: Compression) -> Result<Vec<u8>, PngError> {
+  let mut encoder = ZlibEncoder::new(Vec::new(), compression);
+
+  encoder.write_all(data)?;
+
+  Ok(encoder.finish()?)
+}
+
+/// Decode the zlib stream of the concatenated IDAT chunks into image samples
+pub(crate) fn decode(header: &ChunkImageHeader, compressed: &[u8]) -> Result<Vec<u8>, PngError> {
+  let data = inflate(compressed)?;
+
+  let width = header.width() as usize;
+  let height = header.height() as usize;
+  let channels = header.color_channels() as usize;
+  let bit_depth = header.bit_depth();
+  let bpp = filter_bpp(channels, bit_depth);
+  let pixel_bytes = channels * if bit_depth == 16 { 2 } else { 1 };
+
+  let passes = match header.interface_method() {
+    1 => ADAM7_PASSES.to_vec(),
+    _ => vec![(0, 0, 1, 1)],
+  };
+
+  let mut samples = vec![0; width * height * pixel_bytes];
+  let mut offset = 0;
+
+  for (x0, y0, dx, dy) in passes {
+    let pass_width = if width > x0 { (width - x0).div_ceil(dx) } else { 0 };
+    let pass_height = if height > y0 { (height - y0).div_ceil(dy) } else { 0 };
+
+    if pass_width == 0 || pass_height == 0 {
+      continue;
+    }
+
+    let scanline_len = row_bytes(pass_width, channels, bit_depth);
+    let mut prev_scanline = vec![0; scanline_len];
+
+    for row in 0..pass_height {
+      let Some(line) = data.get(offset..offset + 1 + scanline_len) else {
+        return Err(PngError::InvalidImageData)
+      };
+
+      let filter_type = FilterType::try_from(line[0])?;
+      let mut scanline = line[1..].to_vec();
+      reverse_filter(&filter_type, &mut scanline, &prev_scanline, bpp);
+
+      let row_samples = unpack_row(&scanline, pass_width * channels, bit_depth);
+      let y = y0 + row * dy;
+
+      for (col, pixel) in row_samples.chunks(pixel_bytes).enumerate() {
+        let x = x0 + col * dx;
+        let at = (y * width + x) * pixel_bytes;
+        samples[at..at + pixel_bytes].copy_from_slice(pixel);
+      }
+
+      offset += 1 + scanline_len;
+      prev_scanline = scanline;
+    }
+  }
+
+  Ok(samples)
+}
+
+/// Passes of the image described by `header` that hold pixels, as the byte length of their scanlines and their number of rows
+fn passes(header: &ChunkImageHeader) -> impl Iterator<Item = (usize, usize)> {
+  let width = header.width() as usize;
+  let height = header.height() as usize;
+  let channels = header.color_channels() as usize;
+  let bit_depth = header.bit_depth();
+
+  let passes = match header.interface_method() {
+    1 => ADAM7_PASSES.to_vec(),
+    _ => vec![(0, 0, 1, 1)],
+  };
+
+  passes.into_iter()
+    .filter(move |&(x0, y0, _, _)| width > x0 && height > y0)
+    .map(move |(x0, y0, dx, dy)| (row_bytes((width - x0).div_ceil(dx), channels, bit_depth), (height - y0).div_ceil(dy)))
+}
+
+/// Length of every scanline of the image described by `header`, in stream order and without the filter type bytes
+pub(crate) fn scanline_lengths(header: &ChunkImageHeader) -> impl Iterator<Item = usize> {
+  passes(header).flat_map(|(scanline_len, rows)| std::iter::repeat_n(scanline_len, rows))
+}
+
+/// Decompressed size of the image data described by `header`, filter type bytes included
+pub(crate) fn image_data_len(header: &ChunkImageHeader) -> u64 {
+  passes(header)
+    .map(|(scanline_len, rows)| (1 + scanline_len as u64).saturating_mul(rows as u64))
+    .fold(0, u64::saturating_add)
+}
+
+/// Unfiltered, non-interlaced scanlines of `image`, without filter type bytes
+pub(crate) fn scanlines(image: &Image) -> Vec<Vec<u8>> {
+  let row_len = image.width() as usize * image.channels() * image.bytes_per_sample();
+
+  if row_len == 0 {
+    return vec![vec![]; image.height() as usize];
+  }
+
+  image.samples()
+    .chunks(row_len)
+    .map(|row| pack_row(row, image.bit_depth()))
+    .collect()
+}
+
+/// Pick the filter of `scanline` following `strategy`
+fn filter_scanline(image: &Image, strategy: &FilterStrategy, scanline: &[u8], prev_scanline: &[u8], bpp: usize) -> (FilterType, Vec<u8>) {
+  if let FilterStrategy::Fixed(filter_type) = strategy {
+    return (*filter_type, apply_filter(filter_type, scanline, prev_scanline, bpp))
+  }
+
+  if image.color_type() == ColorType::PaletteIndex || image.bit_depth() < 8 {
+    return (FilterType::None, scanline.to_vec())
+  }
+
+  FILTER_TYPES.iter()
+    .map(|filter_type| (*filter_type, apply_filter(filter_type, scanline, prev_scanline, bpp)))
+    .min_by_key(|(_, filtered)| filtered.iter().map(|&v| (v as i8).unsigned_abs() as u64).sum::<u64>())
+    .unwrap()
+}
+
+/// Filter and compress `image` into a non-interlaced zlib stream
+pub(crate) fn encode(image: &Image, strategy: &FilterStrategy, compression: Compression) -> Result<Vec<u8>, PngError> {
+  let bpp = filter_bpp(image.channels(), image.bit_depth());
+  let rows = scanlines(image);
+
+  let mut filtered: Vec<u8> = Vec::with_capacity(rows.iter().map(|row| row.len() + 1).sum());
+  let mut prev_scanline = vec![0; rows.first().map_or(0, |row| row.len())];
+
+  for row in rows {
+    let (filter_type, bytes) = filter_scanline(image, strategy, &row, &prev_scanline, bpp);
+
+    filtered.push(filter_type.into());
+    filtered.extend(bytes);
+
+    prev_scanline = row;
+  }
+
+  deflate(&filtered, compression)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_pack_unpack_row() {
+    let samples = vec![1, 0, 3, 2, 1];
+    let packed = pack_row(&samples, 2);
+    assert_eq!(packed, vec![0b0100_1110, 0b0100_0000]);
+    assert_eq!(unpack_row(&packed, samples.len(), 2), samples);
+  }
+
+  #[test]
+  fn test_encode_decode() {
+    let samples: Vec<u8> = (0..5 * 3 * 3).map(|v| (v * 13) as u8).collect();
+    let image = Image::new(5, 3, ColorType::Rgb, 8, samples.clone()).unwrap();
+    let header = ChunkImageHeader::new(5, 3, 8, ColorType::Rgb, 0).unwrap();
+
+    for strategy in [FilterStrategy::MinSum, FilterStrategy::Fixed(FilterType::Paeth)] {
+      let compressed = encode(&image, &strategy, Compression::default()).unwrap();
+
+      assert_eq!(decode(&header, &compressed).unwrap(), samples);
+    }
+  }
+
+  #[test]
+  fn test_decode_interlaced() {
+    // 3x3 grayscale, Adam7 passes 2 and 3 are empty
+    let header = ChunkImageHeader::new(3, 3, 8, ColorType::Grayscale, 1).unwrap();
+    let raw: Vec<u8> = vec![
+      0, 1,       // pass 1: (0, 0)
+      0, 3,       // pass 4: (2, 0)
+      0, 7, 9,    // pass 5: (0, 2), (2, 2)
+      0, 2,       // pass 6: (1, 0)
+      0, 8,       // pass 6: (1, 2)
+      0, 4, 5, 6, // pass 7: (0, 1), (1, 1), (2, 1)
+    ];
+    let compressed = deflate(&raw, Compression::default()).unwrap();
+
+    assert_eq!(decode(&header, &compressed).unwrap(), vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+  }
+}
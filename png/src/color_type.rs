@@ -72,6 +72,19 @@ impl ColorType {
       Self::RgbWithAlpha => 4,
     }
   }
+
+  /// Whether the PNG spec allows `bit_depth` for this color type
+  pub fn allows_bit_depth(&self, bit_depth: u8) -> bool {
+    match self {
+      Self::Grayscale => matches!(bit_depth, 1 | 2 | 4 | 8 | 16),
+      Self::PaletteIndex => matches!(bit_depth, 1 | 2 | 4 | 8),
+      Self::Rgb | Self::GrayscaleWithAlpha | Self::RgbWithAlpha => matches!(bit_depth, 8 | 16),
+    }
+  }
+
+  pub fn has_alpha(&self) -> bool {
+    matches!(self, Self::GrayscaleWithAlpha | Self::RgbWithAlpha)
+  }
 }
 
 mod tests {
@@ -104,6 +117,15 @@ mod tests {
     assert_eq!(ColorType::GrayscaleWithAlpha.to_string(), "Grayscale(with alpha)");
     assert_eq!(ColorType::RgbWithAlpha.to_string(), "RGBA");
   }
+
+  #[test]
+  fn test_allows_bit_depth() {
+    assert!(ColorType::Grayscale.allows_bit_depth(2));
+    assert!(ColorType::PaletteIndex.allows_bit_depth(4));
+    assert!(!ColorType::PaletteIndex.allows_bit_depth(16));
+    assert!(!ColorType::Rgb.allows_bit_depth(4));
+    assert!(ColorType::RgbWithAlpha.allows_bit_depth(16));
+  }
 }
 
 
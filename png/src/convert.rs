@@ -0,0 +1,291 @@
+// Color type and bit depth conversion
+
+use std::collections::HashMap;
+
+use crate::chunk::palette::{ChunkPalette, Palette};
+use crate::chunk::transparency::ChunkTransparency;
+use crate::color_type::ColorType;
+use crate::error::PngError;
+use crate::image::Image;
+
+/// Scale `value` from `from` bits to `to` bits, rounding to the nearest value
+pub fn scale_sample(value: u16, from: u8, to: u8) -> u16 {
+  if from == to {
+    return value
+  }
+
+  let from_max = (1u32 << from) - 1;
+  let to_max = (1u32 << to) - 1;
+
+  ((value as u32 * to_max + from_max / 2) / from_max) as u16
+}
+
+/// Luma of an RGB color, using the ITU-R BT.601 weights suggested by the PNG spec
+pub fn luma(red: u16, green: u16, blue: u16) -> u16 {
+  ((red as u32 * 299 + green as u32 * 587 + blue as u32 * 114 + 500) / 1000) as u16
+}
+
+impl Image {
+  /// Every pixel as 16 bit RGBA.
+  /// Palette indices are resolved through PLTE and tRNS, and tRNS color keys become alpha 0.
+  pub fn to_rgba16(&self) -> Result<Vec<[u16; 4]>, PngError> {
+    let depth = self.bit_depth();
+    let scale = |v: u16| scale_sample(v, depth, 16);
+    let color_type = self.color_type();
+    let key = self.transparency().and_then(|t| t.color_key(&color_type));
+
+    (0..self.pixel_count())
+      .map(|i| {
+        let keyed = |samples: &[u16]| key.as_ref().is_some_and(|k| k[..] == *samples);
+
+        let pixel = match color_type {
+          ColorType::Grayscale => {
+            let v = self.sample(i, 0);
+            let alpha = if keyed(&[v]) { 0 } else { u16::MAX };
+            [scale(v), scale(v), scale(v), alpha]
+          },
+          ColorType::Rgb => {
+            let rgb = [self.sample(i, 0), self.sample(i, 1), self.sample(i, 2)];
+            let alpha = if keyed(&rgb) { 0 } else { u16::MAX };
+            [scale(rgb[0]), scale(rgb[1]), scale(rgb[2]), alpha]
+          },
+          ColorType::PaletteIndex => {
+            let index = self.sample(i, 0) as usize;
+            let palette = self.palette()
+              .ok_or(PngError::ChunkNotFoundError)?
+              .get(index)
+              .ok_or(PngError::InvalidImageData)?;
+            let alpha = self.transparency()
+              .and_then(|t| t.get_transparency(&color_type, index))
+              .copied()
+              .unwrap_or(255);
+
+            [palette.red(), palette.green(), palette.blue(), alpha].map(|v| v as u16 * 257)
+          },
+          ColorType::GrayscaleWithAlpha => {
+            let v = scale(self.sample(i, 0));
+            [v, v, v, scale(self.sample(i, 1))]
+          },
+          ColorType::RgbWithAlpha => [0, 1, 2, 3].map(|c| scale(self.sample(i, c))),
+        };
+
+        Ok(pixel)
+      })
+      .collect()
+  }
+
+  /// Build an image of `color_type` and `bit_depth` from 16 bit RGBA pixels.
+  /// Grayscale and RGB targets keep transparency as a tRNS color key when [`color_key`] finds one,
+  /// otherwise alpha is dropped.
+  pub fn from_rgba16(width: u32, height: u32, pixels: &[[u16; 4]], color_type: ColorType, bit_depth: u8) -> Result<Image, PngError> {
+    if !color_type.allows_bit_depth(bit_depth) {
+      return Err(PngError::InvalidBitDepth)
+    }
+
+    if pixels.len() != width as usize * height as usize {
+      return Err(PngError::InvalidImageData)
+    }
+
+    if color_type == ColorType::PaletteIndex {
+      return from_rgba16_indexed(width, height, pixels, bit_depth)
+    }
+
+    let channels: Vec<u16> = pixels.iter()
+      .flat_map(|&[r, g, b, a]| match color_type {
+        ColorType::Grayscale => vec![luma(r, g, b)],
+        ColorType::GrayscaleWithAlpha => vec![luma(r, g, b), a],
+        ColorType::Rgb => vec![r, g, b],
+        _ => vec![r, g, b, a],
+      })
+      .map(|v| scale_sample(v, 16, bit_depth))
+      .collect();
+
+    let samples: Vec<u8> = match bit_depth {
+      16 => channels.iter().flat_map(|v| v.to_be_bytes()).collect(),
+      _ => channels.iter().map(|&v| v as u8).collect(),
+    };
+
+    let mut image = Image::new(width, height, color_type.clone(), bit_depth, samples)?;
+
+    if !color_type.has_alpha() {
+      image.set_transparency(color_key(pixels, &color_type, bit_depth)
+        .map(|key| ChunkTransparency::new(key.iter().flat_map(|v| v.to_be_bytes()).collect())));
+    }
+
+    Ok(image)
+  }
+
+  /// Convert into `color_type` at `bit_depth`.
+  /// Converting into a palette image fails if there are more colors than the bit depth can index.
+  pub fn convert(&self, color_type: ColorType, bit_depth: u8) -> Result<Image, PngError> {
+    if color_type == self.color_type() && bit_depth == self.bit_depth() {
+      return Ok(self.clone())
+    }
+
+    let pixels = self.to_rgba16()?;
+
+    Image::from_rgba16(self.width(), self.height(), &pixels, color_type, bit_depth)
+  }
+}
+
+/// Color key standing for the transparent `pixels` once stored as grayscale or RGB at `bit_depth`.
+/// Found when every pixel is either opaque or fully transparent, the transparent ones share one color
+/// and no opaque pixel has that color.
+pub(crate) fn color_key(pixels: &[[u16; 4]], color_type: &ColorType, bit_depth: u8) -> Option<Vec<u16>> {
+  let color = |&[r, g, b, _]: &[u16; 4]| -> [u16; 3] {
+    match color_type {
+      ColorType::Grayscale => [luma(r, g, b), 0, 0],
+      _ => [r, g, b],
+    }.map(|v| scale_sample(v, 16, bit_depth))
+  };
+
+  let mut key: Option<[u16; 3]> = None;
+
+  for pixel in pixels.iter().filter(|pixel| pixel[3] == 0) {
+    match key {
+      None => key = Some(color(pixel)),
+      Some(key) if key == color(pixel) => {},
+      Some(_) => return None,
+    }
+  }
+
+  let key = key?;
+
+  if pixels.iter().any(|pixel| pixel[3] != 0 && (pixel[3] != u16::MAX || color(pixel) == key)) {
+    return None
+  }
+
+  match color_type {
+    ColorType::Grayscale => Some(vec![key[0]]),
+    ColorType::Rgb => Some(key.to_vec()),
+    _ => None,
+  }
+}
+
+/// Exact palette of the 8 bit colors, in order of first appearance
+fn from_rgba16_indexed(width: u32, height: u32, pixels: &[[u16; 4]], bit_depth: u8) -> Result<Image, PngError> {
+  let max_entries = 1usize << bit_depth;
+
+  let mut entries: Vec<[u8; 4]> = vec![];
+  let mut lookup: HashMap<[u8; 4], u8> = HashMap::new();
+  let mut samples: Vec<u8> = Vec::with_capacity(pixels.len());
+
+  for pixel in pixels {
+    let color = pixel.map(|v| scale_sample(v, 16, 8) as u8);
+
+    let index = match lookup.get(&color) {
+      Some(&index) => index,
+      None => {
+        if entries.len() == max_entries {
+          return Err(PngError::TooManyColors)
+        }
+
+        let index = entries.len() as u8;
+        entries.push(color);
+        lookup.insert(color, index);
+        index
+      },
+    };
+
+    samples.push(index);
+  }
+
+  let mut image = Image::new(width, height, ColorType::PaletteIndex, bit_depth, samples)?;
+
+  image.set_palette(Some(ChunkPalette::new(
+    entries.iter().map(|&[r, g, b, _]| Palette::new(r, g, b)).collect()
+  )));
+  image.set_transparency(palette_transparency(&entries));
+
+  Ok(image)
+}
+
+/// tRNS of RGBA palette entries, truncated after the last translucent one
+pub(crate) fn palette_transparency(entries: &[[u8; 4]]) -> Option<ChunkTransparency> {
+  let len = entries.iter().rposition(|entry| entry[3] != 255)? + 1;
+
+  Some(ChunkTransparency::new(entries[..len].iter().map(|entry| entry[3]).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rgba_image() -> Image {
+    let samples = vec![
+      255, 0, 0, 255,
+      0, 255, 0, 128,
+      0, 0, 255, 0,
+      255, 0, 0, 255,
+    ];
+
+    Image::new(2, 2, ColorType::RgbWithAlpha, 8, samples).unwrap()
+  }
+
+  #[test]
+  fn test_scale_sample() {
+    assert_eq!(scale_sample(255, 8, 16), 65535);
+    assert_eq!(scale_sample(0x1234, 16, 8), 0x12);
+    assert_eq!(scale_sample(3, 2, 8), 255);
+    assert_eq!(scale_sample(1, 1, 8), 255);
+  }
+
+  #[test]
+  fn test_rgba_to_palette_and_back() {
+    let image = rgba_image();
+    let indexed = image.convert(ColorType::PaletteIndex, 2).unwrap();
+
+    assert_eq!(indexed.samples(), &[0, 1, 2, 0]);
+    assert_eq!(indexed.palette().unwrap().len(), 3);
+    assert_eq!(indexed.transparency().unwrap().get_transparency(&ColorType::PaletteIndex, 1), Some(&128));
+
+    let back = indexed.convert(ColorType::RgbWithAlpha, 8).unwrap();
+    assert_eq!(back.samples(), image.samples());
+  }
+
+  #[test]
+  fn test_too_many_colors() {
+    let image = rgba_image();
+    assert!(matches!(image.convert(ColorType::PaletteIndex, 1), Err(PngError::TooManyColors)));
+  }
+
+  #[test]
+  fn test_gray_rgb_round_trip() {
+    let gray = Image::new(3, 1, ColorType::Grayscale, 8, vec![0, 100, 255]).unwrap();
+    let rgb = gray.convert(ColorType::Rgb, 8).unwrap();
+    assert_eq!(rgb.samples(), &[0, 0, 0, 100, 100, 100, 255, 255, 255]);
+    assert_eq!(rgb.convert(ColorType::Grayscale, 8).unwrap().samples(), gray.samples());
+  }
+
+  #[test]
+  fn test_strip_alpha_and_depth() {
+    let rgb16 = rgba_image().convert(ColorType::Rgb, 16).unwrap();
+    assert_eq!(&rgb16.samples()[0..6], &[255, 255, 0, 0, 0, 0]);
+
+    let rgb8 = rgb16.convert(ColorType::Rgb, 8).unwrap();
+    assert_eq!(rgb8.samples(), &[255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 0, 0]);
+  }
+
+  #[test]
+  fn test_color_key_becomes_alpha() {
+    let mut gray = Image::new(2, 1, ColorType::Grayscale, 8, vec![7, 8]).unwrap();
+    gray.set_transparency(Some(ChunkTransparency::new(vec![0, 7])));
+
+    let gray_alpha = gray.convert(ColorType::GrayscaleWithAlpha, 8).unwrap();
+    assert_eq!(gray_alpha.samples(), &[7, 0, 8, 255]);
+  }
+
+  #[test]
+  fn test_color_key_is_carried() {
+    let mut rgb = Image::new(2, 1, ColorType::Rgb, 8, vec![1, 2, 3, 4, 5, 6]).unwrap();
+    rgb.set_transparency(Some(ChunkTransparency::new(vec![0, 1, 0, 2, 0, 3])));
+
+    let rgb16 = rgb.convert(ColorType::Rgb, 16).unwrap();
+    assert_eq!(rgb16.transparency().unwrap().color_key(&ColorType::Rgb), Some(vec![257, 514, 771]));
+    assert_eq!(rgb16.convert(ColorType::RgbWithAlpha, 8).unwrap().samples(), &[1, 2, 3, 0, 4, 5, 6, 255]);
+
+    // translucent pixels leave no key
+    let translucent = rgba_image().convert(ColorType::Rgb, 8).unwrap();
+    assert!(translucent.transparency().is_none());
+  }
+}
@@ -0,0 +1,460 @@
+// Chunk level comparison of two PNGs
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Display;
+
+use crate::chunk::{Chunk, ChunkData};
+use crate::codec;
+use crate::Png;
+
+/// A field of a chunk with different values in the two PNGs, `None` where the field is missing
+pub struct FieldChange {
+  pub field: String,
+  pub before: Option<String>,
+  pub after: Option<String>,
+}
+
+/// A chunk, named by its type and, for text chunks, its keyword
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkName {
+  pub chunk_type: String,
+  pub keyword: Option<String>,
+}
+
+impl Display for ChunkName {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+    match &self.keyword {
+      Some(keyword) => write!(f, "{} {:?}", self.chunk_type, keyword),
+      None => write!(f, "{}", self.chunk_type),
+    }
+  }
+}
+
+/// Change to a chunk other than IDAT. Indices are positions in the chunk lists.
+pub enum ChunkChange {
+  Added { name: ChunkName, index: usize },
+  Removed { name: ChunkName, index: usize },
+  Moved { name: ChunkName, from: usize, to: usize },
+  Changed { name: ChunkName, fields: Vec<FieldChange> },
+}
+
+impl Display for FieldChange {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+    let value = |value: &Option<String>| value.clone().unwrap_or_else(|| String::from("(none)"));
+
+    write!(f, "{}: {} -> {}", self.field, value(&self.before), value(&self.after))
+  }
+}
+
+impl Display for ChunkChange {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+    match self {
+      ChunkChange::Added { name, index } => write!(f, "+ {} (chunk {})", name, index),
+      ChunkChange::Removed { name, index } => write!(f, "- {} (chunk {})", name, index),
+      ChunkChange::Moved { name, from, to } => write!(f, "~ {} moved from chunk {} to {}", name, from, to),
+      ChunkChange::Changed { name, fields } => {
+        write!(f, "* {}", name)?;
+
+        for field in fields.iter() {
+          write!(f, "\n    {}", field)?;
+        }
+
+        Ok(())
+      },
+    }
+  }
+}
+
+/// Comparison of the decoded pixels, whatever the color type and bit depth they are stored with
+#[derive(Debug, PartialEq)]
+pub enum PixelChange {
+  Same,
+  Differ { count: usize, total: usize, first: (u32, u32) },
+  SizeDiffers,
+  /// Either image data does not decode
+  NotDecodable,
+}
+
+impl Display for PixelChange {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+    match self {
+      PixelChange::Same => write!(f, "same"),
+      PixelChange::Differ { count, total, first: (x, y) } => write!(f, "{} of {} differ, the first at x {}, y {}", count, total, x, y),
+      PixelChange::SizeDiffers => write!(f, "not comparable, the image sizes differ"),
+      PixelChange::NotDecodable => write!(f, "not comparable, the image data does not decode"),
+    }
+  }
+}
+
+pub struct PngDiff {
+  pub changes: Vec<ChunkChange>,
+  /// Whether the IDAT chunks inflate to the same data. `None` if either stream does not inflate.
+  pub same_image_data: Option<bool>,
+  pub pixels: PixelChange,
+  /// Number of IDAT chunks in each PNG
+  pub image_data_chunks: (usize, usize),
+}
+
+impl PngDiff {
+  pub fn is_empty(&self) -> bool {
+    self.changes.is_empty() && self.same_image_data == Some(true)
+  }
+}
+
+impl Display for PngDiff {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+    for change in self.changes.iter() {
+      writeln!(f, "{}", change)?;
+    }
+
+    let (a, b) = self.image_data_chunks;
+    let chunks = match a == b {
+      true => String::new(),
+      false => format!(", {} -> {} chunks", a, b),
+    };
+
+    match self.same_image_data {
+      Some(true) => writeln!(f, "IDAT: same decompressed data{}", chunks)?,
+      Some(false) => writeln!(f, "IDAT: decompressed data differs{}", chunks)?,
+      None => writeln!(f, "IDAT: not comparable, the data does not decompress{}", chunks)?,
+    }
+
+    write!(f, "Pixels: {}", self.pixels)
+  }
+}
+
+fn pixel_change(a: &Png, b: &Png) -> PixelChange {
+  let (Ok(a), Ok(b)) = (a.image(), b.image()) else { return PixelChange::NotDecodable };
+
+  if a.width() != b.width() || a.height() != b.height() {
+    return PixelChange::SizeDiffers
+  }
+
+  let (Ok(a_pixels), Ok(b_pixels)) = (a.to_rgba16(), b.to_rgba16()) else { return PixelChange::NotDecodable };
+
+  let mut differing = a_pixels.iter().zip(b_pixels.iter()).enumerate()
+    .filter(|(_, (a, b))| a != b)
+    .map(|(i, _)| i);
+
+  match differing.next() {
+    None => PixelChange::Same,
+    Some(first) => PixelChange::Differ {
+      count: 1 + differing.count(),
+      total: a_pixels.len(),
+      first: ((first % a.width() as usize) as u32, (first / a.width() as usize) as u32),
+    },
+  }
+}
+
+fn name_of(chunk: &Chunk) -> ChunkName {
+  let chunk_type = chunk.chunk_type().to_string();
+  let keyword = match chunk_type.as_str() {
+    "tEXt" | "zTXt" | "iTXt" => text_fields(chunk).into_iter().find(|(field, _)| field == "keyword").map(|(_, value)| value),
+    _ => None,
+  };
+
+  ChunkName { chunk_type, keyword }
+}
+
+/// Split `data` at the first null byte
+fn split_null(data: &[u8]) -> (&[u8], &[u8]) {
+  match data.iter().position(|&b| b == 0) {
+    Some(end) => (&data[..end], &data[end + 1..]),
+    None => (data, &[]),
+  }
+}
+
+fn latin1(bytes: &[u8]) -> String {
+  bytes.iter().map(|&b| b as char).collect()
+}
+
+fn inflated_text(bytes: &[u8]) -> String {
+  codec::inflate(bytes).map_or(String::from("(invalid compressed text)"), |text| String::from_utf8_lossy(&text).into_owned())
+}
+
+/// Fields of tEXt, zTXt and iTXt chunks, with compressed text inflated
+fn text_fields(chunk: &Chunk) -> Vec<(String, String)> {
+  let data = chunk.data();
+  let (keyword, rest) = split_null(&data);
+  let mut fields = vec![(String::from("keyword"), latin1(keyword))];
+
+  match chunk.chunk_type().to_string().as_str() {
+    "tEXt" => fields.push((String::from("text"), latin1(rest))),
+    "zTXt" => fields.push((String::from("text"), inflated_text(rest.get(1..).unwrap_or_default()))),
+    _ => {
+      let compressed = rest.first() == Some(&1);
+      let (language, rest) = split_null(rest.get(2..).unwrap_or_default());
+      let (translated_keyword, text) = split_null(rest);
+
+      fields.push((String::from("language"), latin1(language)));
+      fields.push((String::from("translated keyword"), String::from_utf8_lossy(translated_keyword).into_owned()));
+      fields.push((String::from("text"), match compressed {
+        true => inflated_text(text),
+        false => String::from_utf8_lossy(text).into_owned(),
+      }));
+    },
+  }
+
+  fields
+}
+
+/// Named values of the fields of `chunk`; the data as a whole for chunk types without known fields
+fn fields_of(chunk: &Chunk) -> Vec<(String, String)> {
+  let data = chunk.data();
+  let field = |name: &str, value: String| (name.to_string(), value);
+  let be_u32 = |at: usize| data.get(at..at + 4).map_or(String::new(), |b| u32::from_be_bytes(b.try_into().unwrap()).to_string());
+
+  let mut fields = match (chunk.chunk_type().to_string().as_str(), chunk.chunk_data()) {
+    (_, ChunkData::ImageHeader(header)) => vec![
+      field("width", header.width().to_string()),
+      field("height", header.height().to_string()),
+      field("bit depth", header.bit_depth().to_string()),
+      field("color type", header.color_type().to_string()),
+      field("compression method", header.compression_method().to_string()),
+      field("filter method", header.filter_method().to_string()),
+      field("interlace method", header.interface_method().to_string()),
+    ],
+    ("PLTE", _) => std::iter::once(field("entries", (data.len() / 3).to_string()))
+      .chain(data.chunks_exact(3).enumerate().map(|(i, rgb)| {
+        field(&format!("entry {}", i), format!("#{:02x}{:02x}{:02x}", rgb[0], rgb[1], rgb[2]))
+      }))
+      .collect(),
+    ("tRNS", _) => std::iter::once(field("entries", data.len().to_string()))
+      .chain(data.iter().enumerate().map(|(i, value)| field(&format!("entry {}", i), value.to_string())))
+      .collect(),
+    ("tEXt" | "zTXt" | "iTXt", _) => text_fields(chunk),
+    ("gAMA", _) => vec![field("gamma", be_u32(0))],
+    ("sRGB", _) => vec![field("rendering intent", data.first().map_or(String::new(), |b| b.to_string()))],
+    ("pHYs", _) => vec![
+      field("x", be_u32(0)),
+      field("y", be_u32(4)),
+      field("unit", data.get(8).map_or(String::new(), |b| b.to_string())),
+    ],
+    ("iCCP", _) => vec![field("profile name", latin1(split_null(&data).0))],
+    _ => vec![],
+  };
+
+  fields.push(field("data", format!("{} bytes, crc {:08x}", chunk.length(), chunk.crc())));
+
+  fields
+}
+
+/// Fields with different values in `a` and `b`, in field order. The data is reported only if no other field differs.
+fn field_changes(a: &Chunk, b: &Chunk) -> Vec<FieldChange> {
+  let (a_fields, b_fields) = (fields_of(a), fields_of(b));
+
+  let mut names: Vec<&String> = a_fields.iter().map(|(name, _)| name).collect();
+  names.extend(b_fields.iter().map(|(name, _)| name).filter(|name| !a_fields.iter().any(|(n, _)| n == *name)));
+
+  let value = |fields: &[(String, String)], name: &str| fields.iter().find(|(n, _)| n == name).map(|(_, v)| v.clone());
+
+  let changes: Vec<FieldChange> = names.into_iter()
+    .map(|name| FieldChange { field: name.clone(), before: value(&a_fields, name), after: value(&b_fields, name) })
+    .filter(|change| change.before != change.after)
+    .collect();
+
+  match changes.iter().any(|change| change.field != "data") {
+    true => changes.into_iter().filter(|change| change.field != "data").collect(),
+    false => changes,
+  }
+}
+
+/// Indices into `values` of a longest strictly increasing subsequence
+fn longest_increasing(values: &[usize]) -> Vec<usize> {
+  // tails[k]: index of the smallest tail of an increasing run of length k + 1
+  let mut tails: Vec<usize> = vec![];
+  let mut previous: Vec<Option<usize>> = vec![None; values.len()];
+
+  for (i, &value) in values.iter().enumerate() {
+    let k = tails.partition_point(|&t| values[t] < value);
+
+    previous[i] = k.checked_sub(1).map(|k| tails[k]);
+
+    match k == tails.len() {
+      true => tails.push(i),
+      false => tails[k] = i,
+    }
+  }
+
+  let mut run = vec![];
+  let mut at = tails.last().copied();
+
+  while let Some(i) = at {
+    run.push(i);
+    at = previous[i];
+  }
+
+  run.reverse();
+  run
+}
+
+impl Png {
+  /// Changes from `self` to `other`. Chunks are matched by type, keyword for text chunks, and order of occurrence.
+  /// IDAT chunks are compared as one inflated stream, and as decoded pixels.
+  pub fn diff(&self, other: &Png) -> PngDiff {
+    let is_image_data = |chunk: &Chunk| chunk.chunk_type().to_string() == "IDAT";
+
+    let named = |png: &Png| -> Vec<(usize, ChunkName)> {
+      png.chunks().iter().enumerate()
+        .filter(|(_, chunk)| !is_image_data(chunk))
+        .map(|(i, chunk)| (i, name_of(chunk)))
+        .collect()
+    };
+
+    let (a_named, b_named) = (named(self), named(other));
+
+    // occurrences of each name in b, consumed in order
+    let mut b_by_name: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, name) in b_named.iter().rev() {
+      b_by_name.entry(name.to_string()).or_default().push(*i);
+    }
+
+    let mut changes = vec![];
+    let mut pairs: Vec<(usize, usize)> = vec![];
+
+    for (i, name) in a_named.iter() {
+      match b_by_name.get_mut(&name.to_string()).and_then(|indices| indices.pop()) {
+        Some(j) => pairs.push((*i, j)),
+        None => changes.push(ChunkChange::Removed { name: name.clone(), index: *i }),
+      }
+    }
+
+    let matched: Vec<usize> = pairs.iter().map(|(_, j)| *j).collect();
+
+    for (j, name) in b_named.iter().filter(|(j, _)| !matched.contains(j)) {
+      changes.push(ChunkChange::Added { name: name.clone(), index: *j });
+    }
+
+    let in_order = longest_increasing(&matched);
+
+    for (k, &(i, j)) in pairs.iter().enumerate() {
+      let name = name_of(&self.chunks()[i]);
+
+      if in_order.binary_search(&k).is_err() {
+        changes.push(ChunkChange::Moved { name: name.clone(), from: i, to: j });
+      }
+
+      let fields = field_changes(&self.chunks()[i], &other.chunks()[j]);
+
+      if !fields.is_empty() {
+        changes.push(ChunkChange::Changed { name, fields });
+      }
+    }
+
+    let same_image_data = match (codec::inflate(&self.compressed_data()), codec::inflate(&other.compressed_data())) {
+      (Ok(a), Ok(b)) => Some(a == b),
+      _ => None,
+    };
+
+    let count = |png: &Png| png.chunks().iter().filter(|chunk| is_image_data(chunk)).count();
+
+    PngDiff { changes, same_image_data, pixels: pixel_change(self, other), image_data_chunks: (count(self), count(other)) }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::color_type::ColorType;
+  use crate::image::Image;
+
+  fn text(keyword: &str, text: &str) -> Chunk {
+    Chunk::new("tEXt".parse().unwrap(), format!("{}\0{}", keyword, text).into_bytes())
+  }
+
+  fn testing_png(samples: Vec<u8>, chunks: Vec<Chunk>) -> Png {
+    let image = Image::new(2, 2, ColorType::Grayscale, 8, samples).unwrap();
+    let mut png = Png::from_image(&image).unwrap();
+
+    for chunk in chunks {
+      let end = png.chunk_position("IEND").unwrap();
+      png.insert_chunk(end, chunk);
+    }
+
+    png
+  }
+
+  #[test]
+  fn test_identical() {
+    let a = testing_png(vec![1, 2, 3, 4], vec![text("Author", "me")]);
+    let diff = a.diff(&a);
+
+    assert!(diff.is_empty());
+    assert_eq!(diff.image_data_chunks, (1, 1));
+    assert!(diff.pixels == PixelChange::Same);
+  }
+
+  #[test]
+  fn test_changes() {
+    let a = testing_png(vec![1, 2, 3, 4], vec![text("Author", "me"), text("Title", "t"), Chunk::new("ruSt".parse().unwrap(), vec![1])]);
+    let b = testing_png(vec![1, 2, 3, 5], vec![Chunk::new("ruSt".parse().unwrap(), vec![2]), text("Author", "you"), text("Comment", "c")]);
+
+    let diff = a.diff(&b);
+
+    assert_eq!(diff.same_image_data, Some(false));
+    assert!(diff.pixels == PixelChange::Differ { count: 1, total: 4, first: (1, 1) });
+
+    let removed: Vec<String> = diff.changes.iter().filter_map(|c| match c {
+      ChunkChange::Removed { name, .. } => Some(name.to_string()),
+      _ => None,
+    }).collect();
+    assert_eq!(removed, vec!["tEXt \"Title\""]);
+
+    let added: Vec<String> = diff.changes.iter().filter_map(|c| match c {
+      ChunkChange::Added { name, .. } => Some(name.to_string()),
+      _ => None,
+    }).collect();
+    assert_eq!(added, vec!["tEXt \"Comment\""]);
+
+    assert_eq!(diff.changes.iter().filter(|c| matches!(c, ChunkChange::Moved { .. })).count(), 1);
+
+    let author = diff.changes.iter().find_map(|c| match c {
+      ChunkChange::Changed { name, fields } if name.keyword.as_deref() == Some("Author") => Some(fields),
+      _ => None,
+    }).unwrap();
+    assert_eq!(author.len(), 1);
+    assert_eq!(author[0].field, "text");
+    assert_eq!(author[0].before.as_deref(), Some("me"));
+    assert_eq!(author[0].after.as_deref(), Some("you"));
+
+    // unknown chunks compare their data
+    assert!(diff.changes.iter().any(|c| matches!(c, ChunkChange::Changed { name, fields } if name.chunk_type == "ruSt" && fields[0].field == "data")));
+  }
+
+  #[test]
+  fn test_header_fields() {
+    let a = testing_png(vec![0; 4], vec![]);
+    let b = Png::from_image(&Image::new(4, 1, ColorType::Grayscale, 8, vec![0; 4]).unwrap()).unwrap();
+
+    let diff = a.diff(&b);
+    let fields: Vec<&str> = diff.changes.iter().flat_map(|c| match c {
+      ChunkChange::Changed { fields, .. } => fields.iter().map(|f| f.field.as_str()).collect(),
+      _ => vec![],
+    }).collect();
+
+    assert_eq!(fields, vec!["width", "height"]);
+    // rows of another width inflate to other bytes
+    assert_eq!(diff.same_image_data, Some(false));
+    assert!(diff.pixels == PixelChange::SizeDiffers);
+  }
+
+  #[test]
+  fn test_same_pixels_in_another_format() {
+    let a = testing_png(vec![0, 85, 170, 255], vec![]);
+    let rgb = a.image().unwrap().convert(ColorType::Rgb, 16).unwrap();
+    let b = Png::from_image(&rgb).unwrap();
+
+    let diff = a.diff(&b);
+
+    assert_eq!(diff.same_image_data, Some(false));
+    assert!(diff.pixels == PixelChange::Same);
+    assert!(diff.to_string().ends_with("Pixels: same"));
+  }
+
+  #[test]
+  fn test_longest_increasing() {
+    assert_eq!(longest_increasing(&[3, 0, 1, 4, 2]), vec![1, 2, 4]);
+    assert!(longest_increasing(&[]).is_empty());
+  }
+}
@@ -3,19 +3,40 @@ use std::fmt::Display;
 
 #[derive(Debug)]
 pub enum PngError {
+  InvalidAttachment,
   InvalidBitDepth,
+  InvalidChannel,
   InvalidColorType,
   InvalidCompressionMehtod,
   InvalidFilterMethod,
   InvalidFilterType,
   InvalidHeader,
+  InvalidImageData,
   InvalidInterlaceMethod,
+  InvalidKey,
+  InvalidNetpbm(String),
+  InvalidQoi(String),
+  InvalidSignature,
   ChunkCrcMismatch,
   ChunkNotFoundError,
   ChunkParseError,
   ChunksIsEmptyError,
   ChunkTypeParseError(String),
+  HiddenPayloadNotFound,
+  DuplicateMessagePart(u32),
   IndexOutOfBounds,
+  MissingMessagePart(u32),
+  TooManyColors,
+  DecryptionError,
+  InvalidCompressedPayload,
+  InvalidEnvelope,
+  InvalidMessagePart,
+  KeyDerivationError,
+  PassphraseRequired,
+  PayloadTooLarge(usize),
+  SignatureNotFound,
+  UnknownCompressionMethod(u8),
+  UnsupportedEnvelopeVersion(u8),
   IoError(std::io::Error),
   StringFromUtf8Error(std::string::FromUtf8Error),
 }
@@ -25,19 +46,40 @@ impl std::error::Error for PngError {}
 impl Display for PngError {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
     match self {
+      PngError::InvalidAttachment => write!(f, "Invalid attachment"),
       PngError::InvalidBitDepth => write!(f, "Invalid bit depth"),
+      PngError::InvalidChannel => write!(f, "Invalid channel for the color type"),
       PngError::InvalidColorType => write!(f, "Invalid color type"),
       PngError::InvalidCompressionMehtod => write!(f, "Invalid compression method"),
       PngError::InvalidFilterMethod => write!(f, "Invalid filter method"),
       PngError::InvalidFilterType => write!(f, "Invalid filter type"),
       PngError::InvalidHeader => write!(f, "Invalid PNG header"),
+      PngError::InvalidImageData => write!(f, "Invalid image data"),
       PngError::InvalidInterlaceMethod => write!(f, "Invalid interlace method"),
+      PngError::InvalidKey => write!(f, "Invalid Ed25519 key"),
+      PngError::InvalidNetpbm(err) => write!(f, "Not a valid Netpbm file: {}", err),
+      PngError::InvalidQoi(err) => write!(f, "Not a valid QOI file: {}", err),
+      PngError::InvalidSignature => write!(f, "Invalid signature: the signed chunks were altered or signed with another key"),
       PngError::ChunkCrcMismatch => write!(f, "Chunk crc mismatch"),
       PngError::ChunkNotFoundError => write!(f, "Chunk not found"),
       PngError::ChunkParseError => write!(f, "Chunk parse error"),
       PngError::ChunksIsEmptyError => write!(f, "There're no chunks left"),
       PngError::ChunkTypeParseError(err) => write!(f, "Chunk type parse error: {}", err),
+      PngError::HiddenPayloadNotFound => write!(f, "No hidden payload found, the options or key may differ"),
+      PngError::DuplicateMessagePart(index) => write!(f, "Duplicate message part {}", index),
       PngError::IndexOutOfBounds => write!(f, "Index out of bounds"),
+      PngError::MissingMessagePart(index) => write!(f, "Missing message part {}", index),
+      PngError::TooManyColors => write!(f, "Too many colors for the palette"),
+      PngError::DecryptionError => write!(f, "Authentication failed: wrong passphrase or tampered message"),
+      PngError::InvalidCompressedPayload => write!(f, "Not a valid compressed message"),
+      PngError::InvalidEnvelope => write!(f, "Not a valid encrypted message"),
+      PngError::InvalidMessagePart => write!(f, "Invalid message part"),
+      PngError::KeyDerivationError => write!(f, "Key derivation error"),
+      PngError::PassphraseRequired => write!(f, "The message is encrypted, a passphrase is required"),
+      PngError::PayloadTooLarge(capacity) => write!(f, "Payload too large, the capacity is {} bytes", capacity),
+      PngError::SignatureNotFound => write!(f, "Signature not found"),
+      PngError::UnknownCompressionMethod(method) => write!(f, "Unknown message compression method: {}", method),
+      PngError::UnsupportedEnvelopeVersion(version) => write!(f, "Unsupported encrypted message version: {}", version),
       PngError::IoError(err) => write!(f, "Io error: {}", err),
       PngError::StringFromUtf8Error(err) => write!(f, "Convert to utf-8 string error: {}", err)
     }
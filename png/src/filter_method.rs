@@ -1,6 +1,43 @@
-pub fn reverse_sub_filter(scanline: &mut [u8]) {
-  for i in 1..scanline.len() {
-    scanline[i] = scanline[i].wrapping_add(scanline[i - 1]);
+use crate::filter_type::FilterType;
+
+/// Undo the filter of `scanline` in place.
+/// `prev_scanline` must already be unfiltered, and be all zeros for the first row.
+/// `bpp` is the number of bytes per complete pixel, rounded up to 1.
+pub fn reverse_filter(filter_type: &FilterType, scanline: &mut [u8], prev_scanline: &[u8], bpp: usize) {
+  match filter_type {
+    FilterType::None => {},
+    FilterType::Sub => reverse_sub_filter(scanline, bpp),
+    FilterType::Up => reverse_up_filter(scanline, prev_scanline),
+    FilterType::Average => reverse_average_filter(scanline, prev_scanline, bpp),
+    FilterType::Paeth => reverse_paeth_filter(scanline, prev_scanline, bpp),
+  }
+}
+
+/// Filter `scanline` against the unfiltered `prev_scanline`, returning the filtered bytes
+/// (without the leading filter type byte).
+pub fn apply_filter(filter_type: &FilterType, scanline: &[u8], prev_scanline: &[u8], bpp: usize) -> Vec<u8> {
+  (0..scanline.len())
+    .map(|i| {
+      let a = if i >= bpp { scanline[i - bpp] } else { 0 };
+      let b = prev_scanline[i];
+      let c = if i >= bpp { prev_scanline[i - bpp] } else { 0 };
+
+      let predicted = match filter_type {
+        FilterType::None => 0,
+        FilterType::Sub => a,
+        FilterType::Up => b,
+        FilterType::Average => ((a as u16 + b as u16) / 2) as u8,
+        FilterType::Paeth => predict_paeth(a, b, c),
+      };
+
+      scanline[i].wrapping_sub(predicted)
+    })
+    .collect()
+}
+
+pub fn reverse_sub_filter(scanline: &mut [u8], bpp: usize) {
+  for i in bpp..scanline.len() {
+    scanline[i] = scanline[i].wrapping_add(scanline[i - bpp]);
   }
 }
 
@@ -10,23 +47,20 @@ pub fn reverse_up_filter(scanline: &mut [u8], prev_scanline: &[u8]) {
   }
 }
 
-pub fn reverse_average_filter(scanline: &mut [u8], prev_scanline: &[u8]) {
+pub fn reverse_average_filter(scanline: &mut [u8], prev_scanline: &[u8], bpp: usize) {
   for i in 0..scanline.len() {
-    let a = scanline[i] as u16;
+    let a = if i >= bpp { scanline[i - bpp] as u16 } else { 0 };
     let b = prev_scanline[i] as u16;
-    let c = if i > 0 { scanline[i - 1] as u16 } else { 0 };
-    let p = (a + ((b + c) / 2)) as u8;
-    scanline[i] = p;
+    scanline[i] = scanline[i].wrapping_add(((a + b) / 2) as u8);
   }
 }
 
-pub fn reverse_paeth_filter(scanline: &mut [u8], prev_scanline: &[u8]) {
-  for i in 0 ..scanline.len() {
-    let a = scanline.get(i - 1).unwrap_or(&0);
+pub fn reverse_paeth_filter(scanline: &mut [u8], prev_scanline: &[u8], bpp: usize) {
+  for i in 0..scanline.len() {
+    let a = if i >= bpp { scanline[i - bpp] } else { 0 };
     let b = prev_scanline[i];
-    let c = prev_scanline.get(i - 1).unwrap_or(&0);
-    let p = predict_paeth(*a, b, *c);
-    scanline[i] = scanline[i].wrapping_add(p);
+    let c = if i >= bpp { prev_scanline[i - bpp] } else { 0 };
+    scanline[i] = scanline[i].wrapping_add(predict_paeth(a, b, c));
   }
 }
 
@@ -38,10 +72,44 @@ fn predict_paeth(a: u8, b: u8, c: u8) -> u8 {
   let pc = (p - c as i32).abs();
 
   if pa <= pb && pa <= pc {
-    b
+    a
   } else if pb <= pc {
     b
   } else {
     c
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FILTERS: [FilterType; 5] = [
+    FilterType::None,
+    FilterType::Sub,
+    FilterType::Up,
+    FilterType::Average,
+    FilterType::Paeth,
+  ];
+
+  #[test]
+  fn test_filter_round_trip() {
+    let prev: Vec<u8> = (0..24).map(|v| (v * 37 % 251) as u8).collect();
+    let line: Vec<u8> = (0..24).map(|v| (v * 91 % 253) as u8).collect();
+
+    for bpp in [1, 3, 4, 8] {
+      for filter_type in FILTERS.iter() {
+        let mut filtered = apply_filter(filter_type, &line, &prev, bpp);
+        reverse_filter(filter_type, &mut filtered, &prev, bpp);
+        assert_eq!(filtered, line);
+      }
+    }
+  }
+
+  #[test]
+  fn test_predict_paeth() {
+    assert_eq!(predict_paeth(10, 20, 10), 20);
+    assert_eq!(predict_paeth(20, 10, 10), 20);
+    assert_eq!(predict_paeth(10, 10, 20), 10);
+  }
+}
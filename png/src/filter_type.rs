@@ -4,7 +4,7 @@ use std::fmt::Display;
 use crate::PngError;
 
 /// Filter Type
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum FilterType {
   None,
   Sub,
@@ -13,6 +13,25 @@ pub enum FilterType {
   Paeth,
 }
 
+/// How the encoder picks the filter of each scanline
+#[derive(Clone, Copy, PartialEq)]
+pub enum FilterStrategy {
+  /// The same filter for every scanline
+  Fixed(FilterType),
+  /// The filter with the minimum sum of absolute differences;
+  /// palette and sub-byte images are left unfiltered, as the spec recommends
+  MinSum,
+}
+
+impl Display for FilterStrategy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+    match self {
+      FilterStrategy::Fixed(filter_type) => write!(f, "{}", filter_type),
+      FilterStrategy::MinSum => write!(f, "Adaptive (min sum)"),
+    }
+  }
+}
+
 impl Display for FilterType {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
     match self {
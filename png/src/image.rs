@@ -0,0 +1,133 @@
+use crate::chunk::palette::ChunkPalette;
+use crate::chunk::transparency::ChunkTransparency;
+use crate::color_type::ColorType;
+use crate::error::PngError;
+
+/// Decoded image
+///
+/// Samples are stored unfiltered and unpacked, row by row, left to right: <br/>
+/// bit depth 1, 2, 4, 8: one byte per sample <br/>
+/// bit depth 16: two bytes per sample, big-endian
+#[derive(Clone, PartialEq)]
+pub struct Image {
+  width: u32,
+  height: u32,
+  bit_depth: u8,
+  color_type: ColorType,
+  samples: Vec<u8>,
+  palette: Option<ChunkPalette>,
+  transparency: Option<ChunkTransparency>,
+}
+
+impl Image {
+  pub fn new(width: u32, height: u32, color_type: ColorType, bit_depth: u8, samples: Vec<u8>) -> Result<Self, PngError> {
+    if !color_type.allows_bit_depth(bit_depth) {
+      return Err(PngError::InvalidBitDepth)
+    }
+
+    let bytes_per_sample = if bit_depth == 16 { 2 } else { 1 };
+    let expected = width as usize * height as usize * color_type.channels() as usize * bytes_per_sample;
+
+    if samples.len() != expected {
+      return Err(PngError::InvalidImageData)
+    }
+
+    let max = ((1u32 << bit_depth) - 1) as u8;
+
+    if bit_depth < 8 && samples.iter().any(|&v| v > max) {
+      return Err(PngError::InvalidImageData)
+    }
+
+    Ok(Self {
+      width,
+      height,
+      bit_depth,
+      color_type,
+      samples,
+      palette: None,
+      transparency: None,
+    })
+  }
+
+  pub fn width(&self) -> u32 {
+    self.width
+  }
+
+  pub fn height(&self) -> u32 {
+    self.height
+  }
+
+  pub fn bit_depth(&self) -> u8 {
+    self.bit_depth
+  }
+
+  pub fn color_type(&self) -> ColorType {
+    self.color_type.clone()
+  }
+
+  pub fn channels(&self) -> usize {
+    self.color_type.channels() as usize
+  }
+
+  pub fn bytes_per_sample(&self) -> usize {
+    if self.bit_depth == 16 { 2 } else { 1 }
+  }
+
+  pub fn pixel_count(&self) -> usize {
+    self.width as usize * self.height as usize
+  }
+
+  /// Largest sample value at this bit depth
+  pub fn max_sample(&self) -> u16 {
+    ((1u32 << self.bit_depth) - 1) as u16
+  }
+
+  pub fn samples(&self) -> &[u8] {
+    &self.samples
+  }
+
+  pub fn samples_mut(&mut self) -> &mut [u8] {
+    &mut self.samples
+  }
+
+  pub fn into_samples(self) -> Vec<u8> {
+    self.samples
+  }
+
+  /// Value of sample `channel` of pixel number `index`
+  pub fn sample(&self, index: usize, channel: usize) -> u16 {
+    let at = (index * self.channels() + channel) * self.bytes_per_sample();
+
+    if self.bit_depth == 16 {
+      u16::from_be_bytes([self.samples[at], self.samples[at + 1]])
+    } else {
+      self.samples[at] as u16
+    }
+  }
+
+  pub fn set_sample(&mut self, index: usize, channel: usize, value: u16) {
+    let at = (index * self.channels() + channel) * self.bytes_per_sample();
+
+    if self.bit_depth == 16 {
+      self.samples[at..at + 2].copy_from_slice(&value.to_be_bytes());
+    } else {
+      self.samples[at] = value as u8;
+    }
+  }
+
+  pub fn palette(&self) -> Option<&ChunkPalette> {
+    self.palette.as_ref()
+  }
+
+  pub fn set_palette(&mut self, palette: Option<ChunkPalette>) {
+    self.palette = palette;
+  }
+
+  pub fn transparency(&self) -> Option<&ChunkTransparency> {
+    self.transparency.as_ref()
+  }
+
+  pub fn set_transparency(&mut self, transparency: Option<ChunkTransparency>) {
+    self.transparency = transparency;
+  }
+}
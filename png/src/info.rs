@@ -0,0 +1,195 @@
+// Structured description of the chunks, for machine-readable output
+
+use std::fmt;
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::chunk::{Chunk, CHUNK_CRC_BYTE_LEN, CHUNK_LENGTH_BYTE_LEN, CHUNK_TYPE_BYTE_LEN};
+use crate::chunk::image_header::{ChunkImageHeader, IMAGE_HEADER_CHUNK_DATA_LEN};
+use crate::Png;
+
+#[derive(Serialize)]
+pub struct PngInfo {
+  /// Size of the encoded file in bytes
+  pub size: usize,
+  pub chunks: Vec<ChunkInfo>,
+}
+
+#[derive(Serialize)]
+pub struct ChunkInfo {
+  #[serde(rename = "type")]
+  pub chunk_type: String,
+  pub critical: bool,
+  pub public: bool,
+  pub safe_to_copy: bool,
+  /// Offset of the chunk length field in the file it was read from, null for chunks created since
+  pub offset: Option<usize>,
+  /// Length of the chunk data
+  pub length: u32,
+  pub crc: u32,
+  /// Decoded fields, for the chunk types that have any
+  pub fields: Option<ChunkFields>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChunkFields {
+  Header {
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    color_type: u8,
+    interlace_method: u8,
+  },
+  Palette {
+    entries: usize,
+  },
+  Transparency {
+    entries: usize,
+  },
+  Text {
+    keyword: String,
+  },
+  Gamma {
+    gamma: u32,
+  },
+  Srgb {
+    rendering_intent: u8,
+  },
+  IccProfile {
+    name: String,
+  },
+  PhysicalDimensions {
+    x: u32,
+    y: u32,
+    unit: u8,
+  },
+}
+
+impl ChunkFields {
+  /// Fields of `chunk`, if its type is one of the described ones and its data is well formed
+  pub fn of(chunk: &Chunk) -> Option<Self> {
+    Self::of_data(&chunk.chunk_type().to_string(), &chunk.data())
+  }
+
+  /// Fields of a chunk of `chunk_type` holding `data`, which need not be a valid chunk
+  pub fn of_data(chunk_type: &str, data: &[u8]) -> Option<Self> {
+    match chunk_type {
+      "IHDR" => {
+        let header = ChunkImageHeader::try_from(<[u8; IMAGE_HEADER_CHUNK_DATA_LEN]>::try_from(data).ok()?).ok()?;
+
+        Some(ChunkFields::Header {
+          width: header.width(),
+          height: header.height(),
+          bit_depth: header.bit_depth(),
+          color_type: header.color_type().into(),
+          interlace_method: header.interface_method(),
+        })
+      },
+      "PLTE" => Some(ChunkFields::Palette { entries: data.len() / 3 }),
+      "tRNS" => Some(ChunkFields::Transparency { entries: data.len() }),
+      "tEXt" | "zTXt" | "iTXt" => {
+        let end = data.iter().position(|&b| b == 0)?;
+        Some(ChunkFields::Text { keyword: String::from_utf8_lossy(&data[..end]).into_owned() })
+      },
+      "gAMA" => Some(ChunkFields::Gamma { gamma: u32::from_be_bytes(data.get(..4)?.try_into().ok()?) }),
+      "sRGB" => Some(ChunkFields::Srgb { rendering_intent: *data.first()? }),
+      "iCCP" => {
+        let end = data.iter().position(|&b| b == 0)?;
+        Some(ChunkFields::IccProfile { name: String::from_utf8_lossy(&data[..end]).into_owned() })
+      },
+      "pHYs" if data.len() == 9 => Some(ChunkFields::PhysicalDimensions {
+        x: u32::from_be_bytes(data[0..4].try_into().ok()?),
+        y: u32::from_be_bytes(data[4..8].try_into().ok()?),
+        unit: data[8],
+      }),
+      _ => None,
+    }
+  }
+}
+
+impl Display for ChunkFields {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+    match self {
+      ChunkFields::Header { width, height, bit_depth, color_type, interlace_method } => {
+        write!(f, "{}x{}, {}-bit, color type {}", width, height, bit_depth, color_type)?;
+        if *interlace_method == 1 {
+          write!(f, ", interlaced")?;
+        }
+        Ok(())
+      },
+      ChunkFields::Palette { entries } => write!(f, "{} entries", entries),
+      ChunkFields::Transparency { entries } => write!(f, "{} bytes of alpha", entries),
+      ChunkFields::Text { keyword } => write!(f, "keyword {:?}", keyword),
+      ChunkFields::Gamma { gamma } => write!(f, "gamma {:.5}", *gamma as f64 / 100000.0),
+      ChunkFields::Srgb { rendering_intent } => write!(f, "rendering intent {}", rendering_intent),
+      ChunkFields::IccProfile { name } => write!(f, "profile {:?}", name),
+      ChunkFields::PhysicalDimensions { x, y, unit } => {
+        write!(f, "{}x{} pixels per {}", x, y, if *unit == 1 { "meter" } else { "unit" })
+      },
+    }
+  }
+}
+
+impl Png {
+  /// Description of every chunk, in file order
+  pub fn info(&self) -> PngInfo {
+    let mut size = Png::SIGNATURE.len();
+    let mut chunks = Vec::with_capacity(self.chunks().len());
+
+    for chunk in self.chunks() {
+      let chunk_type = chunk.chunk_type();
+
+      chunks.push(ChunkInfo {
+        chunk_type: chunk_type.to_string(),
+        critical: chunk_type.is_critical(),
+        public: chunk_type.is_public(),
+        safe_to_copy: chunk_type.is_safe_to_copy(),
+        offset: chunk.offset(),
+        length: chunk.length(),
+        crc: chunk.crc(),
+        fields: ChunkFields::of(chunk),
+      });
+
+      size += CHUNK_LENGTH_BYTE_LEN + CHUNK_TYPE_BYTE_LEN + chunk.length() as usize + CHUNK_CRC_BYTE_LEN;
+    }
+
+    PngInfo { size, chunks }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::color_type::ColorType;
+  use crate::image::Image;
+
+  #[test]
+  fn test_info() {
+    let image = Image::new(3, 2, ColorType::Rgb, 8, vec![7; 18]).unwrap();
+    let mut png = Png::from_image(&image).unwrap();
+    png.replace_chunks("tEXt", vec![Chunk::new("tEXt".parse().unwrap(), b"Author\0me".to_vec())]).unwrap();
+
+    // the chunks were not read from a file
+    assert!(png.info().chunks.iter().all(|c| c.offset.is_none()));
+
+    let png = Png::try_from(&png.as_bytes()[..]).unwrap();
+    let info = png.info();
+    let types: Vec<&str> = info.chunks.iter().map(|c| c.chunk_type.as_str()).collect();
+
+    assert_eq!(info.size, png.as_bytes().len());
+    assert_eq!(types.first(), Some(&"IHDR"));
+    assert_eq!(info.chunks[0].offset, Some(8));
+    assert_eq!(info.chunks[1].offset, Some(8 + 12 + 13));
+    assert!(matches!(info.chunks[0].fields, Some(ChunkFields::Header { width: 3, height: 2, bit_depth: 8, color_type: 2, .. })));
+
+    let text = info.chunks.iter().find(|c| c.chunk_type == "tEXt").unwrap();
+    assert!(!text.critical && text.public && text.safe_to_copy);
+    assert!(matches!(&text.fields, Some(ChunkFields::Text { keyword }) if keyword == "Author"));
+
+    let end = info.chunks.last().unwrap();
+    assert_eq!(end.offset.unwrap() + 12, info.size);
+    assert!(end.fields.is_none());
+  }
+}
@@ -1,8 +1,25 @@
+pub mod attachment;
+pub mod check;
 pub mod chunk;
 pub mod color;
 pub mod color_type;
+pub mod convert;
+pub mod diff;
 pub mod filter_type;
-
+pub mod image;
+pub mod info;
+pub mod message;
+pub mod netpbm;
+pub mod optimize;
+pub mod qoi;
+pub mod quantize;
+pub mod reduce;
+pub mod repair;
+pub mod sign;
+pub mod stego;
+pub mod strip;
+
+mod codec;
 mod error;
 mod filter_method;
 
@@ -12,14 +29,47 @@ use std::io::Read;
 use chunk::image_header::ChunkImageHeader;
 use chunk::palette::ChunkPalette;
 use chunk::transparency::ChunkTransparency;
-use flate2::read::ZlibDecoder;
+use flate2::Compression;
 
 use chunk::*;
 use color::*;
 use color_type::*;
-use filter_method::*;
-use filter_type::*;
-use error::*;
+use filter_type::FilterStrategy;
+use image::Image;
+
+pub use error::PngError;
+
+/// Max data length of the IDAT chunks written by the encoder
+const MAX_IDAT_CHUNK_LEN: usize = 1 << 16;
+
+/// Chunks whose content depends on the color type or bit depth
+const IMAGE_FORMAT_DEPENDENT_CHUNKS: [&str; 3] = ["bKGD", "hIST", "sBIT"];
+
+/// Chunks whose content depends on the palette entries
+const PALETTE_DEPENDENT_CHUNKS: [&str; 2] = ["bKGD", "hIST"];
+
+/// Ancillary chunks of the PNG spec. Those marked unsafe to copy are handled by the operations that modify the critical chunks.
+/// APNG chunks are left out, as their frames are not rewritten with the image.
+pub const KNOWN_ANCILLARY_CHUNKS: [&str; 18] = [
+  "cHRM", "gAMA", "iCCP", "sBIT", "sRGB", "cICP", "mDCV", "cLLI", "bKGD",
+  "hIST", "tRNS", "eXIf", "pHYs", "sPLT", "tIME", "iTXt", "tEXt", "zTXt",
+];
+
+/// What happens to unknown ancillary chunks marked unsafe to copy once the critical chunks are modified
+#[derive(Clone, Copy, PartialEq)]
+pub enum UnsafeChunks {
+  /// Drop them, as the PNG spec requires
+  Discard,
+  /// Keep them, for editors that know these chunks stay valid
+  Keep,
+}
+
+/// Whether `chunk_type` is an ancillary chunk unknown to this library and unsafe to copy
+pub fn is_unknown_unsafe_to_copy(chunk_type: &ChunkType) -> bool {
+  !chunk_type.is_critical()
+    && !chunk_type.is_safe_to_copy()
+    && !KNOWN_ANCILLARY_CHUNKS.contains(&chunk_type.to_string().as_str())
+}
 
 pub struct Png {
   chunks: Vec<Chunk>,
@@ -49,7 +99,8 @@ impl TryFrom<&[u8]> for Png {
 
       let chunk_size = CHUNK_LENGTH_BYTE_LEN + CHUNK_TYPE_BYTE_LEN + chunk_data_length as usize + CHUNK_CRC_BYTE_LEN;
 
-      let chunk = Chunk::try_from(&bytes[offset..offset + chunk_size])?;
+      let mut chunk = Chunk::try_from(&bytes[offset..offset + chunk_size])?;
+      chunk.set_offset(offset);
 
       chunks.push(chunk);
 
@@ -69,16 +120,6 @@ impl Display for Png {
   }
 }
 
-fn reverse_filter(scanline: &mut [u8], prev_scanline: &[u8], filter_type: FilterType) {
-  match filter_type {
-    FilterType::None => {},
-    FilterType::Sub => reverse_sub_filter(scanline),
-    FilterType::Up => reverse_up_filter(scanline, prev_scanline),
-    FilterType::Average => reverse_average_filter(scanline, prev_scanline),
-    FilterType::Paeth => reverse_paeth_filter(scanline, prev_scanline),
-  }
-}
-
 impl Png {
   pub const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
 
@@ -141,6 +182,15 @@ impl Png {
     Ok(self.chunks.remove(pos))
   }
 
+  /// Remove every chunk matching `f`, returning the removed chunks in order
+  pub fn remove_chunks_by<F: FnMut(&Chunk) -> bool>(&mut self, mut f: F) -> Vec<Chunk> {
+    let (removed, kept): (Vec<Chunk>, Vec<Chunk>) = self.chunks.drain(..).partition(|chunk| f(chunk));
+
+    self.chunks = kept;
+
+    removed
+  }
+
   pub fn remove_chunk(&mut self, chunk_type: &str) -> Result<Chunk, PngError> {
     let pos = self.chunk_position(chunk_type);
 
@@ -196,53 +246,142 @@ impl Png {
     }
   }
 
-  /// Compose IDAT chunks data into bytes
+  /// Decode IDAT chunks into unfiltered, non-interlaced scanline bytes, without the filter type bytes
   pub fn data(&self) -> Vec<u8> {
-    let data_chunks: Vec<&Chunk> = self.chunks.iter()
+    let image = self.image().expect("Decode image data error");
+
+    codec::scanlines(&image).concat()
+  }
+
+  /// Concatenated data of all IDAT chunks, i.e. the compressed zlib stream
+  pub fn compressed_data(&self) -> Vec<u8> {
+    self.chunks.iter()
       .filter(|chunk| chunk.chunk_type().to_string() == "IDAT")
-      .collect();
+      .flat_map(|chunk| chunk.data())
+      .collect()
+  }
 
-    let data: Vec<u8> = data_chunks.iter().map(|chunk| chunk.data()).flatten().collect();
+  /// Decode the image data, along with PLTE and tRNS
+  pub fn image(&self) -> Result<Image, PngError> {
+    let header = self.header_chunk().ok_or(PngError::ChunkNotFoundError)?;
 
-    let mut decoder = ZlibDecoder::new(&data[..]);
-    let mut buffer: Vec<u8> = vec![];
+    let samples = codec::decode(header, &self.compressed_data())?;
 
-    decoder.read_to_end(&mut buffer).expect("Zlib Decoder read error");
+    let mut image = Image::new(header.width(), header.height(), header.color_type(), header.bit_depth(), samples)?;
 
-    let header_chunk = self.header_chunk().unwrap();
-    let width = header_chunk.width();
-    let color_channels = header_chunk.color_channels();
-    let bit_depth = header_chunk.bit_depth();
+    image.set_palette(self.plte_chunk().cloned());
+    image.set_transparency(self.trns_chunk().cloned());
 
-    let bytes_per_pixel = u32::max(1, (color_channels * bit_depth / 8).into());
+    Ok(image)
+  }
 
-    let bytes_per_row = bytes_per_pixel * width;
-
-    let scanline_bytes = bytes_per_row as usize + 1;
-    let scanline_count = buffer.len() / scanline_bytes;
-
-    for i in 0..scanline_count {
-      let start = i * scanline_bytes;
-      let end = start + scanline_bytes;
-      let mut scanline = buffer[start..end].to_vec();
-      let filter_type = FilterType::try_from(*(&scanline[0])).unwrap();
-
-      if i > 0 {
-        let prev_start = (i - 1) * scanline_bytes;
-        let prev_end = start;
-        let prev_scanline = &buffer[prev_start..prev_end];
-        reverse_filter(&mut scanline, prev_scanline, filter_type);
-      } else {
-        let prev_scanline = vec![0; scanline_bytes];
-        reverse_filter(&mut scanline, &prev_scanline[..], filter_type);
-      }
+  /// Re-encode the image data with `image`.
+  /// IHDR is rewritten, PLTE, tRNS and IDAT chunks are replaced in place,
+  /// with PLTE kept ahead of bKGD, hIST and tRNS and both kept ahead of IDAT,
+  /// bKGD, hIST and sBIT are dropped when the color type or bit depth changes,
+  /// and bKGD and hIST are dropped when the palette changes.
+  /// Unknown chunks unsafe to copy are dropped unless `unsafe_chunks` keeps them.
+  /// Returns the dropped ancillary chunks.
+  pub fn set_image(&mut self, image: &Image, unsafe_chunks: UnsafeChunks) -> Result<Vec<Chunk>, PngError> {
+    let compressed = codec::encode(image, &FilterStrategy::MinSum, Compression::default())?;
+
+    self.set_image_data(image, compressed, unsafe_chunks)
+  }
+
+  /// Replace the image chunks with `image`, whose zlib stream is `compressed`
+  fn set_image_data(&mut self, image: &Image, compressed: Vec<u8>, unsafe_chunks: UnsafeChunks) -> Result<Vec<Chunk>, PngError> {
+    let color_type = image.color_type();
+
+    if color_type == ColorType::PaletteIndex && image.palette().is_none() {
+      return Err(PngError::ChunkNotFoundError)
+    }
+
+    let header = ChunkImageHeader::new(image.width(), image.height(), image.bit_depth(), color_type.clone(), 0)?;
+
+    let format_changed = match self.header_chunk() {
+      Some(old) => old.color_type() != color_type || old.bit_depth() != image.bit_depth(),
+      None => true,
+    };
+    let palette_changed = self.plte_chunk() != image.palette();
+
+    let header_chunk = Chunk::new(ChunkType::try_from(*b"IHDR")?, header.as_bytes());
+
+    let mut palette_chunk = match (&color_type, image.palette()) {
+      (ColorType::PaletteIndex, Some(palette)) => Some(Chunk::new(ChunkType::try_from(*b"PLTE")?, palette.as_bytes())),
+      _ => None,
+    };
+
+    let mut transparency_chunk = match (color_type.has_alpha(), image.transparency()) {
+      (false, Some(trns)) => Some(Chunk::new(ChunkType::try_from(*b"tRNS")?, trns.as_bytes())),
+      _ => None,
+    };
+
+    let mut data_chunks: Vec<Chunk> = vec![];
+
+    for data in compressed.chunks(MAX_IDAT_CHUNK_LEN) {
+      data_chunks.push(Chunk::new(ChunkType::try_from(*b"IDAT")?, data.to_vec()));
+    }
 
-      for (index, b) in scanline.iter().enumerate() {
-        buffer[index] = *b;
+    let mut header_chunk = Some(header_chunk);
+    let mut data_chunks = Some(data_chunks);
+    let mut chunks: Vec<Chunk> = Vec::with_capacity(self.chunks.len());
+    let mut discarded: Vec<Chunk> = vec![];
+
+    for chunk in self.chunks.drain(..) {
+      let chunk_type = chunk.chunk_type().to_string();
+
+      match chunk_type.as_str() {
+        "IHDR" => chunks.extend(header_chunk.take()),
+        "PLTE" => chunks.extend(palette_chunk.take()),
+        "tRNS" => {
+          chunks.extend(palette_chunk.take());
+          chunks.extend(transparency_chunk.take());
+        },
+        "IDAT" => {
+          chunks.extend(palette_chunk.take());
+          chunks.extend(transparency_chunk.take());
+          chunks.extend(data_chunks.take().into_iter().flatten());
+        },
+        t if IMAGE_FORMAT_DEPENDENT_CHUNKS.contains(&t) && format_changed => discarded.push(chunk),
+        t if PALETTE_DEPENDENT_CHUNKS.contains(&t) && palette_changed => discarded.push(chunk),
+        _ if unsafe_chunks == UnsafeChunks::Discard && is_unknown_unsafe_to_copy(chunk.chunk_type()) => discarded.push(chunk),
+        t => {
+          // bKGD and hIST follow PLTE
+          if PALETTE_DEPENDENT_CHUNKS.contains(&t) {
+            chunks.extend(palette_chunk.take());
+          }
+
+          chunks.push(chunk);
+        },
       }
     }
 
-    buffer
+    if let Some(header_chunk) = header_chunk {
+      chunks.insert(0, header_chunk);
+    }
+
+    if let Some(data_chunks) = data_chunks {
+      let end_pos = chunks.iter()
+        .position(|chunk| chunk.chunk_type().to_string() == "IEND")
+        .unwrap_or(chunks.len());
+
+      let image_chunks = palette_chunk.into_iter().chain(transparency_chunk).chain(data_chunks);
+
+      chunks.splice(end_pos..end_pos, image_chunks);
+    }
+
+    self.chunks = chunks;
+
+    Ok(discarded)
+  }
+
+  /// New PNG holding only `image`
+  pub fn from_image(image: &Image) -> Result<Self, PngError> {
+    let mut png = Self::from_chunks(vec![Chunk::new(ChunkType::try_from(*b"IEND")?, vec![])]);
+
+    png.set_image(image, UnsafeChunks::Discard)?;
+
+    Ok(png)
   }
 
   pub fn get_pixel(&self, x: u32, y: u32) -> Result<Color, PngError> {
@@ -295,6 +434,26 @@ impl Png {
       .find(|chunk| chunk.chunk_type().to_string() == chunk_type)
   }
 
+  /// Every chunk of `chunk_type`, in file order
+  pub fn get_chunks(&self, chunk_type: &str) -> Vec<&Chunk> {
+    self.chunks
+      .iter()
+      .filter(|chunk| chunk.chunk_type().to_string() == chunk_type)
+      .collect()
+  }
+
+  /// Replace every chunk of `chunk_type` with `chunks`, at the position of the first one, or before IEND
+  pub fn replace_chunks(&mut self, chunk_type: &str, chunks: Vec<Chunk>) -> Result<(), PngError> {
+    let pos = self.chunk_position(chunk_type)
+      .or_else(|| self.chunk_position("IEND"))
+      .ok_or(PngError::ChunkNotFoundError)?;
+
+    self.remove_chunks_by(|chunk| chunk.chunk_type().to_string() == chunk_type);
+    self.chunks.splice(pos..pos, chunks);
+
+    Ok(())
+  }
+
   pub fn as_bytes(&self) -> Vec<u8> {
     Self::SIGNATURE.iter()
       .map(|v| *v)
@@ -438,6 +597,16 @@ mod tests {
         assert!(chunk.is_none());
     }
 
+    #[test]
+    fn test_remove_chunks_by() {
+        let mut png = testing_png();
+        let removed = png.remove_chunks_by(|chunk| !chunk.chunk_type().is_critical());
+
+        assert_eq!(removed.len(), 1);
+        assert_eq!(&removed[0].chunk_type().to_string(), "miDl");
+        assert_eq!(png.chunks().len(), 2);
+    }
+
     #[test]
     fn test_png_from_image_file() {
         let png = Png::try_from(&PNG_FILE[..]);
@@ -452,6 +621,118 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_chunk_offsets() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+
+        let mut offset = Png::SIGNATURE.len();
+        for chunk in png.chunks() {
+            assert_eq!(chunk.offset(), Some(offset));
+            assert_eq!(&PNG_FILE[offset + 4..offset + 8], &chunk.chunk_type().bytes());
+            offset += chunk.length() as usize + 12;
+        }
+
+        let new_chunk = Chunk::new(ChunkType::from_str("ruSt").unwrap(), vec![]);
+        assert_eq!(new_chunk.offset(), None);
+    }
+
+    #[test]
+    fn test_replace_chunks() {
+        let mut png = testing_png();
+        let chunk = |data: &str| chunk_from_strings("FrSt", data).unwrap();
+
+        png.replace_chunks("FrSt", vec![chunk("a"), chunk("b")]).unwrap();
+
+        let parts: Vec<String> = png.get_chunks("FrSt").iter().map(|c| c.data_as_string().unwrap()).collect();
+        assert_eq!(parts, vec!["a", "b"]);
+        assert_eq!(png.chunk_position("FrSt"), Some(0));
+        assert_eq!(png.chunks().len(), 4);
+
+        // neither a chunk of the type nor IEND to insert before
+        assert!(png.replace_chunks("abCd", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_image_round_trip() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let image = png.image().unwrap();
+
+        assert_eq!(image.width(), 50);
+        assert_eq!(image.samples().len(), 50 * 50 * 4);
+
+        let mut reencoded = Png::try_from(&PNG_FILE[..]).unwrap();
+        reencoded.set_image(&image, UnsafeChunks::Discard).unwrap();
+
+        assert_eq!(reencoded.chunks().len(), png.chunks().len());
+        assert_eq!(reencoded.image().unwrap().samples(), image.samples());
+    }
+
+    #[test]
+    fn test_convert_and_set_image() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let rgb = png.image().unwrap().convert(ColorType::Rgb, 16).unwrap();
+
+        png.set_image(&rgb, UnsafeChunks::Discard).unwrap();
+
+        let header = png.header_chunk().unwrap();
+        assert!(header.color_type() == ColorType::Rgb);
+        assert_eq!(header.bit_depth(), 16);
+        assert_eq!(png.image().unwrap().samples(), rgb.samples());
+    }
+
+    #[test]
+    fn test_set_image_discards_unsafe_chunks() {
+        let image = Image::new(2, 1, ColorType::Grayscale, 8, vec![0, 255]).unwrap();
+        let chunk = |chunk_type: &str| Chunk::new(ChunkType::from_str(chunk_type).unwrap(), vec![1]);
+
+        let mut png = Png::from_image(&image).unwrap();
+        png.insert_chunk(1, chunk("sBIT"));
+        png.insert_chunk(1, chunk("prVt"));
+        png.insert_chunk(1, chunk("prVT"));
+
+        let kept = png.set_image(&image, UnsafeChunks::Keep).unwrap();
+        assert!(kept.is_empty());
+        assert_eq!(png.chunks().len(), 6);
+
+        let rgb = image.convert(ColorType::Rgb, 8).unwrap();
+        let discarded: Vec<String> = png.set_image(&rgb, UnsafeChunks::Discard).unwrap()
+            .iter()
+            .map(|c| c.chunk_type().to_string())
+            .collect();
+
+        assert_eq!(discarded, vec!["prVT", "sBIT"]);
+        assert!(png.get_chunk("prVt").is_some());
+    }
+
+    #[test]
+    fn test_set_image_keeps_palette_order() {
+        let mut image = Image::new(2, 1, ColorType::PaletteIndex, 8, vec![0, 1]).unwrap();
+        image.set_palette(Some(ChunkPalette::new(vec![chunk::palette::Palette::new(255, 0, 0), chunk::palette::Palette::new(0, 0, 255)])));
+
+        let mut png = Png::from_image(&image).unwrap();
+        png.insert_chunk(2, Chunk::new(ChunkType::from_str("bKGD").unwrap(), vec![1]));
+
+        image.set_transparency(Some(ChunkTransparency::new(vec![0])));
+        png.set_image(&image, UnsafeChunks::Discard).unwrap();
+
+        let types: Vec<String> = png.chunks().iter().map(|c| c.chunk_type().to_string()).collect();
+        assert_eq!(types, vec!["IHDR", "PLTE", "bKGD", "tRNS", "IDAT", "IEND"]);
+        assert!(check::check(&png.as_bytes()).is_empty());
+    }
+
+    #[test]
+    fn test_from_image() {
+        let image = Image::new(4, 2, ColorType::Grayscale, 1, vec![0, 1, 1, 0, 1, 0, 0, 1]).unwrap();
+        let png = Png::from_image(&image).unwrap();
+        let types: Vec<String> = png.chunks().iter().map(|c| c.chunk_type().to_string()).collect();
+
+        assert_eq!(types, vec!["IHDR", "IDAT", "IEND"]);
+        assert_eq!(png.data(), vec![0b0110_0000, 0b1001_0000]);
+
+        let decoded = Png::try_from(&png.as_bytes()[..]).unwrap().image().unwrap();
+        assert_eq!(decoded.samples(), image.samples());
+    }
+
     #[test]
     fn test_png_trait_impls() {
         let chunk_bytes: Vec<u8> = testing_chunks()
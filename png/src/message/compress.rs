@@ -0,0 +1,169 @@
+// Compression of message payloads, behind a small header naming the method
+
+use std::fmt;
+use std::fmt::Display;
+use std::io::{Read, Write};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use crate::error::PngError;
+
+/// Marks the data of a chunk as a compressed payload
+pub const PAYLOAD_MAGIC: [u8; 4] = *b"PMCZ";
+
+/// Payload layout: magic, method, uncompressed length (u32, big endian), then the compressed bytes
+const HEADER_LEN: usize = PAYLOAD_MAGIC.len() + 1 + 4;
+
+const ZSTD_LEVEL: i32 = 19;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompressionMethod {
+  Deflate,
+  Zstd,
+  /// Not compressed, only behind the header
+  Stored,
+}
+
+impl TryFrom<u8> for CompressionMethod {
+  type Error = PngError;
+
+  fn try_from(value: u8) -> Result<Self, Self::Error> {
+    match value {
+      0 => Ok(CompressionMethod::Deflate),
+      1 => Ok(CompressionMethod::Zstd),
+      2 => Ok(CompressionMethod::Stored),
+      v => Err(PngError::UnknownCompressionMethod(v)),
+    }
+  }
+}
+
+impl CompressionMethod {
+  fn as_byte(&self) -> u8 {
+    match self {
+      CompressionMethod::Deflate => 0,
+      CompressionMethod::Zstd => 1,
+      CompressionMethod::Stored => 2,
+    }
+  }
+}
+
+impl Display for CompressionMethod {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+    match self {
+      CompressionMethod::Deflate => write!(f, "deflate"),
+      CompressionMethod::Zstd => write!(f, "zstd"),
+      CompressionMethod::Stored => write!(f, "stored"),
+    }
+  }
+}
+
+/// Whether `data` starts with the header written by `compress`
+pub fn is_compressed(data: &[u8]) -> bool {
+  data.starts_with(&PAYLOAD_MAGIC)
+}
+
+/// Compress `payload` with `method`, behind the payload header
+pub fn compress(method: CompressionMethod, payload: &[u8]) -> Result<Vec<u8>, PngError> {
+  let len = u32::try_from(payload.len()).map_err(|_| PngError::InvalidCompressedPayload)?;
+
+  let mut data = Vec::with_capacity(HEADER_LEN + payload.len() / 2);
+  data.extend_from_slice(&PAYLOAD_MAGIC);
+  data.push(method.as_byte());
+  data.extend_from_slice(&len.to_be_bytes());
+
+  match method {
+    CompressionMethod::Deflate => {
+      let mut encoder = DeflateEncoder::new(data, Compression::best());
+      encoder.write_all(payload)?;
+      data = encoder.finish()?;
+    },
+    CompressionMethod::Zstd => {
+      data.extend(zstd::stream::encode_all(payload, ZSTD_LEVEL)?);
+    },
+    CompressionMethod::Stored => data.extend_from_slice(payload),
+  };
+
+  Ok(data)
+}
+
+/// The method named in the header of `data`, if it is a compressed payload
+pub fn compression_method(data: &[u8]) -> Result<CompressionMethod, PngError> {
+  if !is_compressed(data) || data.len() < HEADER_LEN {
+    return Err(PngError::InvalidCompressedPayload)
+  }
+
+  CompressionMethod::try_from(data[PAYLOAD_MAGIC.len()])
+}
+
+/// Inflate a payload written by `compress`, checking its length against the header
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, PngError> {
+  let method = compression_method(data)?;
+
+  let mut len_bytes = [0u8; 4];
+  len_bytes.copy_from_slice(&data[PAYLOAD_MAGIC.len() + 1..HEADER_LEN]);
+  let len = u32::from_be_bytes(len_bytes) as usize;
+
+  let compressed = &data[HEADER_LEN..];
+  // the announced length only bounds the reading, it is not trusted for allocating
+  let mut payload = Vec::with_capacity(len.min(compressed.len().saturating_mul(8)));
+
+  // never read past the announced length, so a forged header can not exhaust memory
+  match method {
+    CompressionMethod::Deflate => DeflateDecoder::new(compressed).take(len as u64 + 1).read_to_end(&mut payload)?,
+    CompressionMethod::Zstd => zstd::stream::Decoder::new(compressed)?.take(len as u64 + 1).read_to_end(&mut payload)?,
+    CompressionMethod::Stored => compressed.take(len as u64 + 1).read_to_end(&mut payload)?,
+  };
+
+  if payload.len() != len {
+    return Err(PngError::InvalidCompressedPayload)
+  }
+
+  Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn manifest() -> Vec<u8> {
+    (0..200).flat_map(|i| format!("{{\"file\": \"frame-{}.png\", \"ok\": true}}\n", i).into_bytes()).collect()
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let payload = manifest();
+
+    for method in [CompressionMethod::Deflate, CompressionMethod::Zstd] {
+      let data = compress(method, &payload).unwrap();
+
+      assert!(is_compressed(&data));
+      assert!(data.len() < payload.len() / 4);
+      assert_eq!(compression_method(&data).unwrap(), method);
+      assert_eq!(decompress(&data).unwrap(), payload);
+    }
+
+    let data = compress(CompressionMethod::Stored, &payload).unwrap();
+    assert_eq!(data.len(), HEADER_LEN + payload.len());
+    assert_eq!(decompress(&data).unwrap(), payload);
+  }
+
+  #[test]
+  fn test_invalid_payload() {
+    assert!(matches!(decompress(b"raw message"), Err(PngError::InvalidCompressedPayload)));
+
+    let mut data = compress(CompressionMethod::Deflate, b"message").unwrap();
+    data[4] = 7;
+    assert!(matches!(decompress(&data), Err(PngError::UnknownCompressionMethod(7))));
+
+    // announced length does not match
+    let mut data = compress(CompressionMethod::Zstd, b"message").unwrap();
+    data[8] = 3;
+    assert!(matches!(decompress(&data), Err(PngError::InvalidCompressedPayload)));
+
+    // a forged length is not allocated up front
+    let mut data = compress(CompressionMethod::Deflate, b"message").unwrap();
+    data[5..9].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(matches!(decompress(&data), Err(PngError::InvalidCompressedPayload)));
+  }
+}
@@ -0,0 +1,118 @@
+// Passphrase encryption of messages: Argon2id key derivation and ChaCha20-Poly1305
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::error::PngError;
+
+/// Marks the data of a chunk as an encrypted envelope
+pub const ENVELOPE_MAGIC: [u8; 4] = *b"PMEC";
+
+/// Envelope layout: magic, version, salt, nonce, then ciphertext with the authentication tag
+pub const ENVELOPE_VERSION: u8 = 1;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+const HEADER_LEN: usize = ENVELOPE_MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+
+/// Argon2id cost of version 1: memory in KiB, iterations and lanes.
+/// Fixed per version, so that raising them later does not break old envelopes.
+const KDF_MEMORY_KIB: u32 = 19 * 1024;
+const KDF_ITERATIONS: u32 = 2;
+const KDF_LANES: u32 = 1;
+
+fn derive_key(passphrase: &[u8], salt: &[u8]) -> Result<Key, PngError> {
+  let params = Params::new(KDF_MEMORY_KIB, KDF_ITERATIONS, KDF_LANES, Some(KEY_LEN))
+    .map_err(|_| PngError::KeyDerivationError)?;
+
+  let mut key = Key::default();
+
+  Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+    .hash_password_into(passphrase, salt, &mut key)
+    .map_err(|_| PngError::KeyDerivationError)?;
+
+  Ok(key)
+}
+
+/// Whether `data` looks like an envelope made by `encrypt`
+pub fn is_encrypted(data: &[u8]) -> bool {
+  data.starts_with(&ENVELOPE_MAGIC)
+}
+
+/// Encrypt `message` into a versioned envelope, with a random salt and nonce
+pub fn encrypt(passphrase: &str, message: &[u8]) -> Result<Vec<u8>, PngError> {
+  let mut salt = [0u8; SALT_LEN];
+  let mut nonce = [0u8; NONCE_LEN];
+  OsRng.fill_bytes(&mut salt);
+  OsRng.fill_bytes(&mut nonce);
+
+  let key = derive_key(passphrase.as_bytes(), &salt)?;
+  let ciphertext = ChaCha20Poly1305::new(&key)
+    .encrypt(Nonce::from_slice(&nonce), message)
+    .map_err(|_| PngError::InvalidEnvelope)?;
+
+  let mut envelope = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+  envelope.extend_from_slice(&ENVELOPE_MAGIC);
+  envelope.push(ENVELOPE_VERSION);
+  envelope.extend_from_slice(&salt);
+  envelope.extend_from_slice(&nonce);
+  envelope.extend_from_slice(&ciphertext);
+
+  Ok(envelope)
+}
+
+/// Decrypt an envelope made by `encrypt`.
+/// Fails with `DecryptionError` if the passphrase is wrong or the envelope was tampered with.
+pub fn decrypt(passphrase: &str, envelope: &[u8]) -> Result<Vec<u8>, PngError> {
+  if !is_encrypted(envelope) || envelope.len() < HEADER_LEN {
+    return Err(PngError::InvalidEnvelope)
+  }
+
+  let version = envelope[ENVELOPE_MAGIC.len()];
+
+  if version != ENVELOPE_VERSION {
+    return Err(PngError::UnsupportedEnvelopeVersion(version))
+  }
+
+  let salt_start = ENVELOPE_MAGIC.len() + 1;
+  let nonce_start = salt_start + SALT_LEN;
+
+  let salt = &envelope[salt_start..nonce_start];
+  let nonce = &envelope[nonce_start..HEADER_LEN];
+  let ciphertext = &envelope[HEADER_LEN..];
+
+  let key = derive_key(passphrase.as_bytes(), salt)?;
+
+  ChaCha20Poly1305::new(&key)
+    .decrypt(Nonce::from_slice(nonce), ciphertext)
+    .map_err(|_| PngError::DecryptionError)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_encrypt_decrypt() {
+    let envelope = encrypt("correct horse", b"hidden message").unwrap();
+
+    assert!(is_encrypted(&envelope));
+    assert_eq!(envelope[4], ENVELOPE_VERSION);
+    assert!(!envelope.windows(6).any(|w| w == b"hidden"));
+    assert_eq!(decrypt("correct horse", &envelope).unwrap(), b"hidden message");
+
+    assert!(matches!(decrypt("battery staple", &envelope), Err(PngError::DecryptionError)));
+  }
+
+  #[test]
+  fn test_decrypt_invalid_envelope() {
+    assert!(matches!(decrypt("x", b"plain text"), Err(PngError::InvalidEnvelope)));
+
+    let mut envelope = encrypt("x", b"").unwrap();
+    envelope[4] = 9;
+    assert!(matches!(decrypt("x", &envelope), Err(PngError::UnsupportedEnvelopeVersion(9))));
+  }
+}
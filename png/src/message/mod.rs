@@ -0,0 +1,156 @@
+// Hidden message payloads stored in custom chunks
+
+pub mod compress;
+pub mod encrypt;
+pub mod split;
+
+use crate::attachment::ATTACHMENT_CHUNK;
+use crate::error::PngError;
+use crate::sign::{IMAGE_SIGNATURE_CHUNK, MESSAGE_SIGNATURE_CHUNK};
+use crate::{Png, KNOWN_ANCILLARY_CHUNKS};
+use compress::CompressionMethod;
+
+/// Chunks of this crate holding something else than messages
+const OTHER_CHUNKS: [&str; 3] = [ATTACHMENT_CHUNK, MESSAGE_SIGNATURE_CHUNK, IMAGE_SIGNATURE_CHUNK];
+
+/// A message found by `Png::messages`
+pub enum Message {
+  Text(String),
+  /// Needs a passphrase to be read
+  Encrypted,
+}
+
+/// Whether `bytes` is UTF-8 text without control characters other than whitespace
+pub fn looks_like_text(bytes: &[u8]) -> bool {
+  std::str::from_utf8(bytes).is_ok_and(|text| text.chars().all(|c| !c.is_control() || c.is_whitespace()))
+}
+
+/// Whether `data` went through compression, encryption or splitting, rather than being plain text
+pub fn is_packed(data: &[u8]) -> bool {
+  compress::is_compressed(data) || encrypt::is_encrypted(data) || split::is_part(data)
+}
+
+/// Chunk data of `message`: compressed first, as ciphertext does not compress, then encrypted.
+/// Uncompressed messages that look packed are stored behind the payload header, so they are not mistaken for one.
+pub fn pack(message: &[u8], compression: Option<CompressionMethod>, passphrase: Option<&str>) -> Result<Vec<u8>, PngError> {
+  let payload = match compression {
+    Some(method) => compress::compress(method, message)?,
+    None if is_packed(message) => compress::compress(CompressionMethod::Stored, message)?,
+    None => message.to_vec(),
+  };
+
+  match passphrase {
+    Some(passphrase) => encrypt::encrypt(passphrase, &payload),
+    None => Ok(payload),
+  }
+}
+
+/// The message of chunk data written by `pack`. Encrypted messages need the passphrase
+pub fn unpack(data: &[u8], passphrase: Option<&str>) -> Result<Vec<u8>, PngError> {
+  let payload = match (encrypt::is_encrypted(data), passphrase) {
+    (true, Some(passphrase)) => encrypt::decrypt(passphrase, data)?,
+    (true, None) => return Err(PngError::PassphraseRequired),
+    (false, _) => data.to_vec(),
+  };
+
+  if compress::is_compressed(&payload) {
+    return compress::decompress(&payload)
+  }
+
+  Ok(payload)
+}
+
+impl Png {
+  /// The message stored in the `chunk_type` chunks: split parts are joined, then decrypted and decompressed.
+  /// Encrypted messages need the passphrase
+  pub fn message(&self, chunk_type: &str, passphrase: Option<&str>) -> Result<Vec<u8>, PngError> {
+    let parts: Vec<Vec<u8>> = self.get_chunks(chunk_type).iter().map(|chunk| chunk.data()).collect();
+
+    split::join(&parts).and_then(|data| unpack(&data, passphrase))
+  }
+
+  /// Messages in the ancillary chunks outside the PNG spec, by chunk type in order of first appearance.
+  /// Chunks are skipped unless they hold text, possibly compressed or split, or an encrypted envelope.
+  pub fn messages(&self) -> Vec<(String, Message)> {
+    let mut chunk_types: Vec<String> = vec![];
+
+    for chunk in self.chunks() {
+      let chunk_type = chunk.chunk_type().to_string();
+
+      if !chunk.chunk_type().is_critical()
+        && !KNOWN_ANCILLARY_CHUNKS.contains(&chunk_type.as_str())
+        && !OTHER_CHUNKS.contains(&chunk_type.as_str())
+        && !chunk_types.contains(&chunk_type) {
+        chunk_types.push(chunk_type);
+      }
+    }
+
+    chunk_types.into_iter()
+      .filter_map(|chunk_type| {
+        let message = match self.message(&chunk_type, None) {
+          Ok(bytes) if looks_like_text(&bytes) => Message::Text(String::from_utf8(bytes).ok()?),
+          Err(PngError::PassphraseRequired) => Message::Encrypted,
+          _ => return None,
+        };
+
+        Some((chunk_type, message))
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_pack_unpack() {
+    let message = b"log line\nlog line\nlog line\nlog line\n";
+
+    let packed = pack(message, Some(CompressionMethod::Deflate), None).unwrap();
+    assert!(compress::is_compressed(&packed));
+    assert_eq!(unpack(&packed, None).unwrap(), message);
+
+    let packed = pack(message, Some(CompressionMethod::Zstd), Some("pw")).unwrap();
+    assert!(encrypt::is_encrypted(&packed));
+    assert!(matches!(unpack(&packed, None), Err(PngError::PassphraseRequired)));
+    assert_eq!(unpack(&packed, Some("pw")).unwrap(), message);
+
+    assert_eq!(unpack(b"plain", None).unwrap(), b"plain");
+
+    // raw messages starting with a magic number are not taken for packed ones
+    for message in [&b"PMCZ\x01 not compressed"[..], b"PMEC not encrypted"] {
+      let packed = pack(message, None, None).unwrap();
+      assert_eq!(unpack(&packed, None).unwrap(), message);
+
+      let packed = pack(message, None, Some("pw")).unwrap();
+      assert_eq!(unpack(&packed, Some("pw")).unwrap(), message);
+    }
+  }
+
+  #[test]
+  fn test_messages() {
+    use crate::chunk::{Chunk, ChunkType};
+    use crate::color_type::ColorType;
+    use crate::image::Image;
+    use std::str::FromStr;
+
+    let mut png = Png::from_image(&Image::new(1, 1, ColorType::Grayscale, 8, vec![0]).unwrap()).unwrap();
+    let chunk = |chunk_type: &str, data: Vec<u8>| Chunk::new(ChunkType::from_str(chunk_type).unwrap(), data);
+
+    png.insert_chunk(1, chunk("ruSt", b"hello".to_vec()));
+    png.insert_chunk(1, chunk("biNy", vec![0, 1, 2, 255]));
+    png.insert_chunk(1, chunk("seCr", pack(b"hidden", None, Some("pw")).unwrap()));
+    png.insert_chunk(1, chunk("tEXt", b"Title\0ignored".to_vec()));
+
+    let messages = png.messages();
+    let chunk_types: Vec<&str> = messages.iter().map(|(t, _)| t.as_str()).collect();
+
+    assert_eq!(chunk_types, vec!["seCr", "ruSt"]);
+    assert!(matches!(messages[0].1, Message::Encrypted));
+    assert!(matches!(&messages[1].1, Message::Text(text) if text == "hello"));
+
+    assert_eq!(png.message("seCr", Some("pw")).unwrap(), b"hidden");
+    assert!(matches!(png.message("miSs", None), Err(PngError::ChunkNotFoundError)));
+  }
+}
@@ -0,0 +1,131 @@
+// Splitting of large message payloads across sequenced chunks of the same type
+
+use std::collections::BTreeMap;
+
+use crate::error::PngError;
+
+/// Marks the data of a chunk as one part of a split payload
+pub const PART_MAGIC: [u8; 4] = *b"PMSQ";
+
+/// Part layout: magic, index and total count (u32, big endian), then the part of the payload
+const HEADER_LEN: usize = PART_MAGIC.len() + 4 + 4;
+
+/// Default max data length of each chunk
+pub const DEFAULT_PART_LEN: usize = 1 << 16;
+
+fn read_u32(bytes: &[u8]) -> u32 {
+  let mut buf = [0u8; 4];
+  buf.copy_from_slice(&bytes[..4]);
+  u32::from_be_bytes(buf)
+}
+
+/// Whether `data` is one part written by `split`
+pub fn is_part(data: &[u8]) -> bool {
+  data.starts_with(&PART_MAGIC) && data.len() >= HEADER_LEN
+}
+
+/// Split `payload` into chunk data of at most `max_part_len` bytes each, headers included.
+/// A payload that fits is returned as a single chunk data, without header.
+pub fn split(payload: &[u8], max_part_len: usize) -> Result<Vec<Vec<u8>>, PngError> {
+  if payload.len() <= max_part_len {
+    return Ok(vec![payload.to_vec()])
+  }
+
+  if max_part_len <= HEADER_LEN {
+    return Err(PngError::InvalidMessagePart)
+  }
+
+  let parts: Vec<&[u8]> = payload.chunks(max_part_len - HEADER_LEN).collect();
+  let total = u32::try_from(parts.len()).map_err(|_| PngError::InvalidMessagePart)?;
+
+  let data = parts.iter()
+    .enumerate()
+    .map(|(index, part)| {
+      let mut data = Vec::with_capacity(HEADER_LEN + part.len());
+      data.extend_from_slice(&PART_MAGIC);
+      data.extend_from_slice(&(index as u32).to_be_bytes());
+      data.extend_from_slice(&total.to_be_bytes());
+      data.extend_from_slice(part);
+      data
+    })
+    .collect();
+
+  Ok(data)
+}
+
+/// Reassemble the payload from the data of its chunks, in any order.
+/// Fails on missing or duplicate parts, or parts announcing different total counts.
+pub fn join(parts: &[Vec<u8>]) -> Result<Vec<u8>, PngError> {
+  match parts {
+    [] => return Err(PngError::ChunkNotFoundError),
+    [data] if !is_part(data) => return Ok(data.clone()),
+    _ => {},
+  }
+
+  let mut ordered: BTreeMap<u32, &[u8]> = BTreeMap::new();
+  let mut total: Option<u32> = None;
+
+  for data in parts {
+    if !is_part(data) {
+      return Err(PngError::InvalidMessagePart)
+    }
+
+    let index = read_u32(&data[PART_MAGIC.len()..]);
+    let count = read_u32(&data[PART_MAGIC.len() + 4..]);
+
+    if *total.get_or_insert(count) != count || index >= count {
+      return Err(PngError::InvalidMessagePart)
+    }
+
+    if ordered.insert(index, &data[HEADER_LEN..]).is_some() {
+      return Err(PngError::DuplicateMessagePart(index))
+    }
+  }
+
+  if let Some(missing) = (0..total.unwrap_or(0)).find(|index| !ordered.contains_key(index)) {
+    return Err(PngError::MissingMessagePart(missing))
+  }
+
+  Ok(ordered.into_values().flatten().copied().collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn payload() -> Vec<u8> {
+    (0..1000u32).map(|i| (i % 251) as u8).collect()
+  }
+
+  #[test]
+  fn test_split_join() {
+    let parts = split(&payload(), 112).unwrap();
+
+    assert_eq!(parts.len(), 10);
+    assert!(parts.iter().all(|part| part.len() <= 112 && is_part(part)));
+
+    let mut shuffled = parts.clone();
+    shuffled.reverse();
+    assert_eq!(join(&shuffled).unwrap(), payload());
+  }
+
+  #[test]
+  fn test_small_payload_is_not_split() {
+    let parts = split(b"short", 112).unwrap();
+
+    assert_eq!(parts, vec![b"short".to_vec()]);
+    assert_eq!(join(&parts).unwrap(), b"short");
+  }
+
+  #[test]
+  fn test_missing_and_duplicate_parts() {
+    let mut parts = split(&payload(), 112).unwrap();
+    let third = parts.remove(3);
+
+    assert!(matches!(join(&parts), Err(PngError::MissingMessagePart(3))));
+
+    parts.push(parts[0].clone());
+    parts.push(third);
+    assert!(matches!(join(&parts), Err(PngError::DuplicateMessagePart(0))));
+  }
+}
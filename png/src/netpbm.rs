@@ -0,0 +1,306 @@
+// Netpbm PGM (P5), PPM (P6) and PAM (P7) formats
+
+use crate::color_type::ColorType;
+use crate::error::PngError;
+use crate::image::Image;
+
+/// Bit depths of PNG, in the order a matching Netpbm max value is looked for
+const BIT_DEPTHS: [u8; 5] = [1, 2, 4, 8, 16];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetpbmFormat {
+  /// Grayscale, P5
+  Pgm,
+  /// RGB, P6
+  Ppm,
+  /// Any number of channels with a tuple type, P7
+  Pam,
+}
+
+impl NetpbmFormat {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      NetpbmFormat::Pgm => "pgm",
+      NetpbmFormat::Ppm => "ppm",
+      NetpbmFormat::Pam => "pam",
+    }
+  }
+
+  fn magic(&self) -> &'static str {
+    match self {
+      NetpbmFormat::Pgm => "P5",
+      NetpbmFormat::Ppm => "P6",
+      NetpbmFormat::Pam => "P7",
+    }
+  }
+}
+
+fn invalid(message: &str) -> PngError {
+  PngError::InvalidNetpbm(message.to_string())
+}
+
+/// Color type and bit depth `image` is written with in `format`
+fn layout(image: &Image, format: NetpbmFormat) -> (ColorType, u8) {
+  let color_type = image.color_type();
+  let gray = matches!(color_type, ColorType::Grayscale | ColorType::GrayscaleWithAlpha);
+  let alpha = matches!(color_type, ColorType::GrayscaleWithAlpha | ColorType::RgbWithAlpha) || image.transparency().is_some();
+
+  // grayscale below 8 bits keeps its depth, as a max value of 1, 3 or 15
+  let bit_depth = match color_type {
+    ColorType::PaletteIndex => 8,
+    _ => image.bit_depth(),
+  };
+  let full_depth = bit_depth.max(8);
+
+  match (format, gray, alpha) {
+    (NetpbmFormat::Pgm, _, _) | (NetpbmFormat::Pam, true, false) => (ColorType::Grayscale, bit_depth),
+    (NetpbmFormat::Ppm, _, _) | (NetpbmFormat::Pam, false, false) => (ColorType::Rgb, full_depth),
+    (NetpbmFormat::Pam, true, true) => (ColorType::GrayscaleWithAlpha, full_depth),
+    (NetpbmFormat::Pam, false, true) => (ColorType::RgbWithAlpha, full_depth),
+  }
+}
+
+fn tuple_type(image: &Image) -> &'static str {
+  match image.color_type() {
+    ColorType::Grayscale if image.bit_depth() == 1 => "BLACKANDWHITE",
+    ColorType::Grayscale | ColorType::PaletteIndex => "GRAYSCALE",
+    ColorType::GrayscaleWithAlpha => "GRAYSCALE_ALPHA",
+    ColorType::Rgb => "RGB",
+    ColorType::RgbWithAlpha => "RGB_ALPHA",
+  }
+}
+
+/// Header tokens separated by whitespace, with `#` comments running to the end of the line
+struct Tokens<'a> {
+  bytes: &'a [u8],
+  at: usize,
+}
+
+impl<'a> Tokens<'a> {
+  fn skip_space(&mut self) {
+    while let Some(&b) = self.bytes.get(self.at) {
+      match b {
+        b'#' => {
+          while self.bytes.get(self.at).is_some_and(|&b| b != b'\n') {
+            self.at += 1;
+          }
+        },
+        _ if b.is_ascii_whitespace() => self.at += 1,
+        _ => break,
+      }
+    }
+  }
+
+  fn next(&mut self) -> Option<&'a str> {
+    self.skip_space();
+
+    let start = self.at;
+
+    while self.bytes.get(self.at).is_some_and(|b| !b.is_ascii_whitespace()) {
+      self.at += 1;
+    }
+
+    std::str::from_utf8(&self.bytes[start..self.at]).ok().filter(|token| !token.is_empty())
+  }
+
+  fn number(&mut self, name: &str) -> Result<u32, PngError> {
+    self.next()
+      .and_then(|token| token.parse().ok())
+      .ok_or_else(|| invalid(&format!("missing or invalid {}", name)))
+  }
+
+  fn skip_line(&mut self) {
+    while self.bytes.get(self.at).is_some_and(|&b| b != b'\n') {
+      self.at += 1;
+    }
+  }
+
+  /// The raster starts after the single whitespace byte ending the header
+  fn raster(&self) -> &'a [u8] {
+    self.bytes.get(self.at + 1..).unwrap_or_default()
+  }
+}
+
+struct Header {
+  width: u32,
+  height: u32,
+  /// Number of channels
+  depth: u32,
+  max: u32,
+}
+
+fn read_header(tokens: &mut Tokens) -> Result<Header, PngError> {
+  let magic = tokens.next().ok_or_else(|| invalid("missing magic number"))?;
+
+  let header = match magic {
+    "P5" | "P6" => Header {
+      width: tokens.number("width")?,
+      height: tokens.number("height")?,
+      depth: if magic == "P5" { 1 } else { 3 },
+      max: tokens.number("max value")?,
+    },
+    "P7" => {
+      let (mut width, mut height, mut depth, mut max) = (None, None, None, None);
+
+      loop {
+        match tokens.next().ok_or_else(|| invalid("missing ENDHDR"))? {
+          "WIDTH" => width = Some(tokens.number("width")?),
+          "HEIGHT" => height = Some(tokens.number("height")?),
+          "DEPTH" => depth = Some(tokens.number("depth")?),
+          "MAXVAL" => max = Some(tokens.number("max value")?),
+          // the channel count is what matters
+          "TUPLTYPE" => tokens.skip_line(),
+          "ENDHDR" => break,
+          token => return Err(invalid(&format!("unknown header line {}", token))),
+        }
+      }
+
+      Header {
+        width: width.ok_or_else(|| invalid("missing WIDTH"))?,
+        height: height.ok_or_else(|| invalid("missing HEIGHT"))?,
+        depth: depth.ok_or_else(|| invalid("missing DEPTH"))?,
+        max: max.ok_or_else(|| invalid("missing MAXVAL"))?,
+      }
+    },
+    _ => return Err(invalid(&format!("unsupported magic number {}, only P5, P6 and P7 are", magic))),
+  };
+
+  if header.width == 0 || header.height == 0 {
+    return Err(invalid("empty image"))
+  }
+
+  if header.max == 0 || header.max > u16::MAX as u32 {
+    return Err(invalid(&format!("max value {} is not between 1 and 65535", header.max)))
+  }
+
+  Ok(header)
+}
+
+impl Image {
+  /// Encode as `format`. Palette images are expanded, PGM keeps the luma only, and PGM and PPM drop alpha and tRNS.
+  pub fn to_netpbm(&self, format: NetpbmFormat) -> Result<Vec<u8>, PngError> {
+    let (color_type, bit_depth) = layout(self, format);
+    let image = self.convert(color_type, bit_depth)?;
+
+    let mut bytes = match format {
+      NetpbmFormat::Pam => format!(
+        "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",
+        image.width(), image.height(), image.channels(), image.max_sample(), tuple_type(&image),
+      ),
+      _ => format!("{}\n{} {}\n{}\n", format.magic(), image.width(), image.height(), image.max_sample()),
+    }.into_bytes();
+
+    // both store 16 bit samples big-endian
+    bytes.extend_from_slice(image.samples());
+
+    Ok(bytes)
+  }
+
+  /// Decode a PGM, PPM or PAM file, with the color type matching its channels.
+  /// Max values other than 2^n - 1 for a bit depth of the color type are scaled to 8 or 16 bits.
+  pub fn from_netpbm(bytes: &[u8]) -> Result<Image, PngError> {
+    let mut tokens = Tokens { bytes, at: 0 };
+    let header = read_header(&mut tokens)?;
+
+    let color_type = match header.depth {
+      1 => ColorType::Grayscale,
+      2 => ColorType::GrayscaleWithAlpha,
+      3 => ColorType::Rgb,
+      4 => ColorType::RgbWithAlpha,
+      depth => return Err(invalid(&format!("{} channels are not supported, only 1 to 4 are", depth))),
+    };
+
+    let bytes_per_sample = if header.max > u8::MAX as u32 { 2 } else { 1 };
+    let len = (header.width as usize).checked_mul(header.height as usize)
+      .and_then(|pixels| pixels.checked_mul(header.depth as usize))
+      .and_then(|samples| samples.checked_mul(bytes_per_sample))
+      .ok_or_else(|| invalid("image too large"))?;
+
+    let raster = tokens.raster().get(..len)
+      .ok_or_else(|| invalid(&format!("the raster is {} bytes, the header implies {}", tokens.raster().len(), len)))?;
+
+    let values: Vec<u32> = match bytes_per_sample {
+      2 => raster.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as u32).collect(),
+      _ => raster.iter().map(|&b| b as u32).collect(),
+    };
+
+    if values.iter().any(|&v| v > header.max) {
+      return Err(invalid("a sample is above the max value"))
+    }
+
+    let exact = BIT_DEPTHS.into_iter()
+      .find(|&depth| (1u32 << depth) - 1 == header.max && color_type.allows_bit_depth(depth));
+
+    if let Some(bit_depth) = exact {
+      return Image::new(header.width, header.height, color_type, bit_depth, raster.to_vec())
+    }
+
+    let bit_depth = if bytes_per_sample == 2 { 16 } else { 8 };
+    let target_max = (1u32 << bit_depth) - 1;
+    let scaled = values.into_iter().map(|v| (v * target_max + header.max / 2) / header.max);
+
+    let samples: Vec<u8> = match bit_depth {
+      16 => scaled.flat_map(|v| (v as u16).to_be_bytes()).collect(),
+      _ => scaled.map(|v| v as u8).collect(),
+    };
+
+    Image::new(header.width, header.height, color_type, bit_depth, samples)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::palette::{ChunkPalette, Palette};
+  use crate::chunk::transparency::ChunkTransparency;
+
+  #[test]
+  fn test_round_trip() {
+    let gray = Image::new(3, 2, ColorType::Grayscale, 4, vec![0, 3, 15, 7, 1, 2]).unwrap();
+    let pgm = gray.to_netpbm(NetpbmFormat::Pgm).unwrap();
+
+    assert!(pgm.starts_with(b"P5\n3 2\n15\n"));
+    assert!(Image::from_netpbm(&pgm).unwrap() == gray);
+
+    let rgba: Vec<u8> = (0..2 * 2 * 4 * 2).collect();
+    let rgba = Image::new(2, 2, ColorType::RgbWithAlpha, 16, rgba).unwrap();
+    let pam = rgba.to_netpbm(NetpbmFormat::Pam).unwrap();
+
+    assert!(pam.starts_with(b"P7\nWIDTH 2\nHEIGHT 2\nDEPTH 4\nMAXVAL 65535\nTUPLTYPE RGB_ALPHA\nENDHDR\n"));
+    assert!(Image::from_netpbm(&pam).unwrap() == rgba);
+
+    // alpha is dropped
+    let ppm = rgba.to_netpbm(NetpbmFormat::Ppm).unwrap();
+    assert!(Image::from_netpbm(&ppm).unwrap() == rgba.convert(ColorType::Rgb, 16).unwrap());
+  }
+
+  #[test]
+  fn test_palette_is_expanded() {
+    let mut image = Image::new(2, 1, ColorType::PaletteIndex, 1, vec![0, 1]).unwrap();
+    image.set_palette(Some(ChunkPalette::new(vec![Palette::new(255, 0, 0), Palette::new(0, 0, 255)])));
+    image.set_transparency(Some(ChunkTransparency::new(vec![128])));
+
+    let pam = image.to_netpbm(NetpbmFormat::Pam).unwrap();
+    let decoded = Image::from_netpbm(&pam).unwrap();
+
+    assert!(decoded.color_type() == ColorType::RgbWithAlpha);
+    assert_eq!(decoded.samples(), &[255, 0, 0, 128, 0, 0, 255, 255]);
+  }
+
+  #[test]
+  fn test_from_netpbm() {
+    // comments, and a max value that is no bit depth
+    let ppm = b"P6 # comment\n1 1\n# another\n1000\n\x01\xf4\x00\x00\x03\xe8";
+    let image = Image::from_netpbm(ppm).unwrap();
+
+    assert_eq!(image.bit_depth(), 16);
+    assert_eq!(image.samples(), &[0x80, 0x00, 0, 0, 0xff, 0xff]);
+
+    assert!(Image::from_netpbm(b"P5\n2 2\n255\n\x00\x01\x02").is_err());
+    assert!(Image::from_netpbm(b"P5\n1 1\n10\n\x0b").is_err());
+    assert!(Image::from_netpbm(b"P3\n1 1\n255\n0 0 0").is_err());
+
+    let huge = b"P7\nWIDTH 4294967295\nHEIGHT 4294967295\nDEPTH 4\nMAXVAL 65535\nENDHDR\n\x00";
+    assert!(matches!(Image::from_netpbm(huge), Err(PngError::InvalidNetpbm(message)) if message == "image too large"));
+  }
+}
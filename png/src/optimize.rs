@@ -0,0 +1,296 @@
+// Lossless optimizations: IDAT recompression and palette cleanup
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use flate2::Compression;
+
+use crate::chunk::{Chunk, ChunkType};
+use crate::chunk::image_header::ChunkImageHeader;
+use crate::chunk::palette::{ChunkPalette, Palette};
+use crate::codec;
+use crate::color_type::ColorType;
+use crate::convert::palette_transparency;
+use crate::error::PngError;
+use crate::filter_type::{FilterStrategy, FilterType};
+use crate::image::Image;
+use crate::{Png, UnsafeChunks};
+
+/// Filter strategies tried by `Png::optimize`
+pub const FILTER_STRATEGIES: [FilterStrategy; 6] = [
+  FilterStrategy::Fixed(FilterType::None),
+  FilterStrategy::Fixed(FilterType::Sub),
+  FilterStrategy::Fixed(FilterType::Up),
+  FilterStrategy::Fixed(FilterType::Average),
+  FilterStrategy::Fixed(FilterType::Paeth),
+  FilterStrategy::MinSum,
+];
+
+/// Deflate levels tried by `Png::optimize`
+pub const COMPRESSION_LEVELS: [u32; 2] = [6, 9];
+
+/// Outcome of `Png::optimize`
+pub struct Optimization {
+  /// The winning strategy and level, `None` if the original IDAT stream was already the smallest
+  pub best: Option<(FilterStrategy, u32)>,
+  /// Length of the zlib stream before
+  pub original_len: usize,
+  /// Length of the zlib stream after
+  pub optimized_len: usize,
+  /// Chunks dropped as the image data changed
+  pub discarded: Vec<Chunk>,
+}
+
+/// Outcome of `Png::optimize_palette`
+pub struct PaletteCleanup {
+  pub original_entries: usize,
+  pub entries: usize,
+  /// Entries used by neither the image data nor bKGD
+  pub unused: usize,
+  /// Entries merged into an earlier one with the same color and alpha
+  pub duplicates: usize,
+  /// Chunks dropped as the palette changed
+  pub discarded: Vec<Chunk>,
+}
+
+impl Png {
+  /// Re-encode the image data with every filter strategy and deflate level,
+  /// keeping the smallest zlib stream. The decoded samples are verified to be identical.
+  /// Unknown chunks unsafe to copy are dropped unless `unsafe_chunks` keeps them.
+  pub fn optimize(&mut self, unsafe_chunks: UnsafeChunks) -> Result<Optimization, PngError> {
+    let image = self.image()?;
+    let original_len = self.compressed_data().len();
+
+    let mut best: Option<(FilterStrategy, u32, Vec<u8>)> = None;
+
+    for strategy in FILTER_STRATEGIES.iter() {
+      for &level in COMPRESSION_LEVELS.iter() {
+        let compressed = codec::encode(&image, strategy, Compression::new(level))?;
+
+        if best.as_ref().is_none_or(|(_, _, smallest)| compressed.len() < smallest.len()) {
+          best = Some((*strategy, level, compressed));
+        }
+      }
+    }
+
+    let Some((strategy, level, compressed)) = best else {
+      return Err(PngError::InvalidImageData)
+    };
+
+    if compressed.len() >= original_len {
+      return Ok(Optimization { best: None, original_len, optimized_len: original_len, discarded: vec![] })
+    }
+
+    let header = ChunkImageHeader::new(image.width(), image.height(), image.bit_depth(), image.color_type(), 0)?;
+
+    if codec::decode(&header, &compressed)? != image.samples() {
+      return Err(PngError::InvalidImageData)
+    }
+
+    let optimized_len = compressed.len();
+
+    let discarded = self.set_image_data(&image, compressed, unsafe_chunks)?;
+
+    Ok(Optimization { best: Some((strategy, level)), original_len, optimized_len, discarded })
+  }
+
+  /// Drop unused and duplicate entries of the palette, then order the entries with alpha first
+  /// so that tRNS can be truncated, and otherwise by usage. The image data, tRNS, hIST and bKGD
+  /// are remapped to the new entries; the decoded colors stay identical.
+  /// Unknown chunks unsafe to copy are dropped unless `unsafe_chunks` keeps them.
+  pub fn optimize_palette(&mut self, unsafe_chunks: UnsafeChunks) -> Result<PaletteCleanup, PngError> {
+    let image = self.image()?;
+    let color_type = image.color_type();
+
+    if color_type != ColorType::PaletteIndex {
+      return Err(PngError::InvalidColorType)
+    }
+
+    let palette = image.palette().ok_or(PngError::ChunkNotFoundError)?;
+    let original_entries = palette.len();
+
+    let alpha = |i: usize| {
+      image.transparency()
+        .and_then(|t| t.get_transparency(&color_type, i))
+        .copied()
+        .unwrap_or(255)
+    };
+
+    let mut usage = vec![0u64; original_entries];
+
+    for &index in image.samples() {
+      *usage.get_mut(index as usize).ok_or(PngError::InvalidImageData)? += 1;
+    }
+
+    let background = self.get_chunk("bKGD")
+      .and_then(|chunk| chunk.data().first().map(|&i| i as usize))
+      .filter(|&i| i < original_entries);
+
+    // distinct colors of the used entries, with their total usage
+    let mut colors: Vec<([u8; 4], u64)> = vec![];
+    let mut lookup: HashMap<[u8; 4], usize> = HashMap::new();
+    let mut color_of: Vec<Option<usize>> = vec![None; original_entries];
+
+    for (i, entry) in palette.palettes().iter().enumerate() {
+      if usage[i] == 0 && background != Some(i) {
+        continue;
+      }
+
+      let key = [entry.red(), entry.green(), entry.blue(), alpha(i)];
+      let color = *lookup.entry(key).or_insert_with(|| {
+        colors.push((key, 0));
+        colors.len() - 1
+      });
+
+      colors[color].1 += usage[i];
+      color_of[i] = Some(color);
+    }
+
+    // stable, so equally used colors keep their order
+    let mut order: Vec<usize> = (0..colors.len()).collect();
+    order.sort_by_key(|&c| (colors[c].0[3] == 255, Reverse(colors[c].1)));
+
+    let mut new_index = vec![0u8; colors.len()];
+
+    for (n, &c) in order.iter().enumerate() {
+      new_index[c] = n as u8;
+    }
+
+    let remap: Vec<Option<u8>> = color_of.iter().map(|c| c.map(|c| new_index[c])).collect();
+
+    let unused = color_of.iter().filter(|c| c.is_none()).count();
+    let mut cleanup = PaletteCleanup {
+      original_entries,
+      entries: colors.len(),
+      unused,
+      duplicates: original_entries - unused - colors.len(),
+      discarded: vec![],
+    };
+
+    if remap.iter().enumerate().all(|(i, &n)| n == Some(i as u8)) {
+      return Ok(cleanup)
+    }
+
+    let samples: Vec<u8> = image.samples().iter().map(|&i| remap[i as usize].unwrap_or(0)).collect();
+    let entries: Vec<[u8; 4]> = order.iter().map(|&c| colors[c].0).collect();
+
+    let mut cleaned = Image::new(image.width(), image.height(), color_type, image.bit_depth(), samples)?;
+    cleaned.set_palette(Some(ChunkPalette::new(
+      entries.iter().map(|&[r, g, b, _]| Palette::new(r, g, b)).collect()
+    )));
+    cleaned.set_transparency(palette_transparency(&entries));
+
+    let histogram = self.get_chunk("hIST").map(|chunk| {
+      let mut frequencies = vec![0u16; entries.len()];
+
+      for (i, v) in chunk.data().chunks_exact(2).enumerate() {
+        if let Some(Some(n)) = remap.get(i) {
+          let f = &mut frequencies[*n as usize];
+          *f = f.saturating_add(u16::from_be_bytes([v[0], v[1]]));
+        }
+      }
+
+      frequencies.iter().flat_map(|f| f.to_be_bytes()).collect::<Vec<u8>>()
+    });
+
+    // drops the old bKGD and hIST, as the palette changes
+    let discarded = self.set_image(&cleaned, unsafe_chunks)?;
+
+    let mut dependent_chunks: Vec<Chunk> = vec![];
+
+    if let Some(Some(index)) = background.map(|i| remap[i]) {
+      dependent_chunks.push(Chunk::new(ChunkType::try_from(*b"bKGD")?, vec![index]));
+    }
+
+    if let Some(histogram) = histogram {
+      dependent_chunks.push(Chunk::new(ChunkType::try_from(*b"hIST")?, histogram));
+    }
+
+    // after PLTE and tRNS, before IDAT
+    let data_pos = self.chunk_position("IDAT").ok_or(PngError::ChunkNotFoundError)?;
+
+    cleanup.discarded = discarded.into_iter()
+      .filter(|chunk| !dependent_chunks.iter().any(|c| c.chunk_type() == chunk.chunk_type()))
+      .collect();
+
+    for chunk in dependent_chunks.into_iter().rev() {
+      self.insert_chunk(data_pos, chunk);
+    }
+
+    Ok(cleanup)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::chunk::{Chunk, ChunkType};
+  use crate::chunk::palette::{ChunkPalette, Palette};
+  use crate::chunk::transparency::ChunkTransparency;
+  use crate::color_type::ColorType;
+  use crate::image::Image;
+  use crate::{Png, UnsafeChunks};
+
+  #[test]
+  fn test_optimize() {
+    let samples: Vec<u8> = (0..32u32 * 32).flat_map(|i| [(i % 32) as u8, (i / 32) as u8, 7]).collect();
+    let image = Image::new(32, 32, ColorType::Rgb, 8, samples).unwrap();
+
+    let mut png = Png::from_image(&image).unwrap();
+    // make the stored stream deliberately poor
+    let stored = super::codec::encode(&image, &super::FILTER_STRATEGIES[0], flate2::Compression::none()).unwrap();
+    png.set_image_data(&image, stored, UnsafeChunks::Discard).unwrap();
+
+    let result = png.optimize(UnsafeChunks::Discard).unwrap();
+
+    assert!(result.best.is_some());
+    assert!(result.optimized_len < result.original_len);
+    assert_eq!(png.compressed_data().len(), result.optimized_len);
+    assert_eq!(png.image().unwrap().samples(), image.samples());
+
+    // already optimal
+    assert!(png.optimize(UnsafeChunks::Discard).unwrap().best.is_none());
+  }
+
+  #[test]
+  fn test_optimize_palette() {
+    // 0: unused but the background, 1 and 2: duplicates, 3: transparent, 4: unused
+    let mut image = Image::new(4, 2, ColorType::PaletteIndex, 4, vec![1, 2, 1, 3, 2, 1, 3, 1]).unwrap();
+    image.set_palette(Some(ChunkPalette::new(vec![
+      Palette::new(9, 9, 9),
+      Palette::new(200, 0, 0),
+      Palette::new(200, 0, 0),
+      Palette::new(0, 0, 0),
+      Palette::new(1, 2, 3),
+    ])));
+    image.set_transparency(Some(ChunkTransparency::new(vec![255, 255, 255, 0])));
+
+    let mut png = Png::from_image(&image).unwrap();
+    let data_pos = png.chunk_position("IDAT").unwrap();
+    png.insert_chunk(data_pos, Chunk::new(ChunkType::try_from(*b"bKGD").unwrap(), vec![0]));
+    png.insert_chunk(data_pos, Chunk::new(ChunkType::try_from(*b"hIST").unwrap(), vec![0, 0, 0, 4, 0, 2, 0, 2, 0, 0]));
+
+    let cleanup = png.optimize_palette(UnsafeChunks::Discard).unwrap();
+
+    assert_eq!(cleanup.original_entries, 5);
+    assert_eq!(cleanup.entries, 3);
+    assert_eq!(cleanup.unused, 1);
+    assert_eq!(cleanup.duplicates, 1);
+    assert!(cleanup.discarded.is_empty());
+
+    let types: Vec<String> = png.chunks().iter().map(|c| c.chunk_type().to_string()).collect();
+    assert_eq!(types, vec!["IHDR", "PLTE", "tRNS", "bKGD", "hIST", "IDAT", "IEND"]);
+
+    let cleaned = png.image().unwrap();
+    assert_eq!(cleaned.samples(), &[1, 1, 1, 0, 1, 1, 0, 1]);
+    assert_eq!(cleaned.transparency().unwrap().get_transparency(&ColorType::PaletteIndex, 0), Some(&0));
+    assert!(cleaned.transparency().unwrap().get_transparency(&ColorType::PaletteIndex, 1).is_none());
+    assert_eq!(png.get_chunk("bKGD").unwrap().data(), vec![2]);
+    assert_eq!(png.get_chunk("hIST").unwrap().data(), vec![0, 2, 0, 6, 0, 0]);
+
+    let rgba = |image: &Image| image.convert(ColorType::RgbWithAlpha, 8).unwrap().into_samples();
+    assert_eq!(rgba(&cleaned), rgba(&image));
+
+    // nothing left to clean
+    assert_eq!(png.optimize_palette(UnsafeChunks::Discard).unwrap().entries, 3);
+    assert_eq!(png.image().unwrap().samples(), cleaned.samples());
+  }
+}
@@ -0,0 +1,313 @@
+// QOI, the Quite OK Image format, for 8 bit RGB and RGBA images
+
+use crate::chunk::Chunk;
+use crate::color_type::ColorType;
+use crate::error::PngError;
+use crate::image::Image;
+use crate::Png;
+
+/// Bytes starting every QOI file
+pub const MAGIC: &[u8; 4] = b"qoif";
+const HEADER_LEN: usize = 14;
+const END_MARKER: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RUN: u8 = 0xc0;
+const OP_RGB: u8 = 0xfe;
+const OP_RGBA: u8 = 0xff;
+/// Mask of the 2 bit tags of the index, diff, luma and run ops
+const OP_MASK: u8 = 0xc0;
+
+const MAX_RUN: u8 = 62;
+
+/// Gamma of sRGB in gAMA units, 1 / 2.2
+const SRGB_GAMMA: u32 = 45455;
+/// Gamma of linear samples in gAMA units, 1.0
+const LINEAR_GAMMA: u32 = 100000;
+
+/// Colorspace flag of the QOI header. Informative only, it does not change how pixels are encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QoiColorspace {
+  /// sRGB color channels with linear alpha
+  Srgb = 0,
+  /// All channels linear
+  Linear = 1,
+}
+
+impl TryFrom<u8> for QoiColorspace {
+  type Error = PngError;
+
+  fn try_from(v: u8) -> Result<Self, <Self as TryFrom<u8>>::Error> {
+    match v {
+      0 => Ok(QoiColorspace::Srgb),
+      1 => Ok(QoiColorspace::Linear),
+      _ => Err(invalid(&format!("unknown colorspace {}", v))),
+    }
+  }
+}
+
+fn invalid(message: &str) -> PngError {
+  PngError::InvalidQoi(message.to_string())
+}
+
+fn hash([r, g, b, a]: [u8; 4]) -> usize {
+  (r as usize * 3 + g as usize * 5 + b as usize * 7 + a as usize * 11) % 64
+}
+
+/// Op bytes of the pixels in `samples`, with `channels` samples per pixel
+fn encode_pixels(samples: &[u8], channels: usize, bytes: &mut Vec<u8>) {
+  let mut index = [[0u8; 4]; 64];
+  let mut prev = [0, 0, 0, 255];
+  let mut run = 0;
+
+  let count = samples.len() / channels;
+
+  for (i, pixel) in samples.chunks_exact(channels).enumerate() {
+    let pixel = [pixel[0], pixel[1], pixel[2], pixel.get(3).copied().unwrap_or(255)];
+
+    if pixel == prev {
+      run += 1;
+
+      if run == MAX_RUN || i + 1 == count {
+        bytes.push(OP_RUN | (run - 1));
+        run = 0;
+      }
+
+      continue;
+    }
+
+    if run > 0 {
+      bytes.push(OP_RUN | (run - 1));
+      run = 0;
+    }
+
+    let at = hash(pixel);
+
+    if index[at] == pixel {
+      bytes.push(OP_INDEX | at as u8);
+    } else if pixel[3] != prev[3] {
+      index[at] = pixel;
+      bytes.extend([OP_RGBA, pixel[0], pixel[1], pixel[2], pixel[3]]);
+    } else {
+      index[at] = pixel;
+
+      // differences wrap around, as the decoder adds them modulo 256
+      let [dr, dg, db] = [0, 1, 2].map(|c| pixel[c].wrapping_sub(prev[c]) as i8);
+      let (dr_dg, db_dg) = (dr.wrapping_sub(dg), db.wrapping_sub(dg));
+
+      if [dr, dg, db].iter().all(|d| (-2..=1).contains(d)) {
+        bytes.push(OP_DIFF | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8);
+      } else if (-32..=31).contains(&dg) && (-8..=7).contains(&dr_dg) && (-8..=7).contains(&db_dg) {
+        bytes.extend([OP_LUMA | (dg + 32) as u8, ((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8]);
+      } else {
+        bytes.extend([OP_RGB, pixel[0], pixel[1], pixel[2]]);
+      }
+    }
+
+    prev = pixel;
+  }
+}
+
+/// Samples of `count` pixels decoded from the op bytes `data`
+fn decode_pixels(data: &[u8], count: usize, channels: usize) -> Result<Vec<u8>, PngError> {
+  let mut samples = Vec::with_capacity(count * channels);
+  let mut index = [[0u8; 4]; 64];
+  let mut pixel = [0, 0, 0, 255];
+  let mut run = 0;
+  let mut at = 0;
+
+  let mut take = |n: usize| -> Result<&[u8], PngError> {
+    let bytes = data.get(at..at + n).ok_or_else(|| invalid("the data ends before the last pixel"))?;
+    at += n;
+    Ok(bytes)
+  };
+
+  for _ in 0..count {
+    if run > 0 {
+      run -= 1;
+    } else {
+      let op = take(1)?[0];
+
+      match (op, op & OP_MASK) {
+        (OP_RGB, _) => pixel[..3].copy_from_slice(take(3)?),
+        (OP_RGBA, _) => pixel.copy_from_slice(take(4)?),
+        (_, OP_INDEX) => pixel = index[op as usize],
+        (_, OP_DIFF) => {
+          for (c, shift) in [4, 2, 0].into_iter().enumerate() {
+            pixel[c] = pixel[c].wrapping_add((op >> shift) & 0x03).wrapping_sub(2);
+          }
+        },
+        (_, OP_LUMA) => {
+          let dg = (op & 0x3f).wrapping_sub(32);
+          let rb = take(1)?[0];
+
+          pixel[0] = pixel[0].wrapping_add(dg).wrapping_add(rb >> 4).wrapping_sub(8);
+          pixel[1] = pixel[1].wrapping_add(dg);
+          pixel[2] = pixel[2].wrapping_add(dg).wrapping_add(rb & 0x0f).wrapping_sub(8);
+        },
+        _ => run = op & 0x3f,
+      }
+
+      index[hash(pixel)] = pixel;
+    }
+
+    samples.extend_from_slice(&pixel[..channels]);
+  }
+
+  Ok(samples)
+}
+
+impl Image {
+  /// Encode as QOI, in RGBA if the image has alpha or tRNS and in RGB otherwise, at 8 bits
+  pub fn to_qoi(&self, colorspace: QoiColorspace) -> Result<Vec<u8>, PngError> {
+    let alpha = matches!(self.color_type(), ColorType::GrayscaleWithAlpha | ColorType::RgbWithAlpha) || self.transparency().is_some();
+    let (color_type, channels) = if alpha { (ColorType::RgbWithAlpha, 4) } else { (ColorType::Rgb, 3) };
+
+    let image = self.convert(color_type, 8)?;
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + image.samples().len() + END_MARKER.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&image.width().to_be_bytes());
+    bytes.extend_from_slice(&image.height().to_be_bytes());
+    bytes.push(channels as u8);
+    bytes.push(colorspace as u8);
+
+    encode_pixels(image.samples(), channels, &mut bytes);
+
+    bytes.extend_from_slice(&END_MARKER);
+
+    Ok(bytes)
+  }
+
+  /// Decode a QOI file into an 8 bit RGB or RGBA image, and the colorspace of its header
+  pub fn from_qoi(bytes: &[u8]) -> Result<(Image, QoiColorspace), PngError> {
+    if bytes.len() < HEADER_LEN + END_MARKER.len() || !bytes.starts_with(MAGIC) {
+      return Err(invalid("missing qoif header"))
+    }
+
+    let width = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+    let height = u32::from_be_bytes(bytes[8..12].try_into().unwrap());
+    let colorspace = QoiColorspace::try_from(bytes[13])?;
+
+    let (color_type, channels) = match bytes[12] {
+      3 => (ColorType::Rgb, 3),
+      4 => (ColorType::RgbWithAlpha, 4),
+      channels => return Err(invalid(&format!("{} channels, not 3 or 4", channels))),
+    };
+
+    if width == 0 || height == 0 {
+      return Err(invalid("empty image"))
+    }
+
+    let data = &bytes[HEADER_LEN..bytes.len() - END_MARKER.len()];
+    let count = width as usize * height as usize;
+
+    // a byte decodes to at most one run of pixels, which catches sizes too large to allocate
+    if count / MAX_RUN as usize > data.len() {
+      return Err(invalid("the data is too short for the image size"))
+    }
+
+    if !bytes.ends_with(&END_MARKER) {
+      return Err(invalid("missing end marker"))
+    }
+
+    let samples = decode_pixels(data, count, channels)?;
+
+    Ok((Image::new(width, height, color_type, 8, samples)?, colorspace))
+  }
+}
+
+impl Png {
+  /// Colorspace QOI records for this PNG: sRGB with an sRGB chunk, linear with a gAMA of 1.0, sRGB otherwise
+  pub fn qoi_colorspace(&self) -> QoiColorspace {
+    let gamma = self.chunks().iter()
+      .find(|chunk| chunk.chunk_type().to_string() == "gAMA")
+      .and_then(|chunk| chunk.data().get(..4).map(|b| u32::from_be_bytes(b.try_into().unwrap())));
+
+    match (self.chunk_position("sRGB"), gamma) {
+      (None, Some(LINEAR_GAMMA)) => QoiColorspace::Linear,
+      _ => QoiColorspace::Srgb,
+    }
+  }
+
+  /// Encode the image as QOI, with `colorspace` or the one given by the sRGB and gAMA chunks
+  pub fn to_qoi(&self, colorspace: Option<QoiColorspace>) -> Result<Vec<u8>, PngError> {
+    self.image()?.to_qoi(colorspace.unwrap_or_else(|| self.qoi_colorspace()))
+  }
+
+  /// PNG of a QOI file. An sRGB image gets sRGB and gAMA chunks, a linear one a gAMA of 1.0.
+  pub fn from_qoi(bytes: &[u8]) -> Result<Png, PngError> {
+    let (image, colorspace) = Image::from_qoi(bytes)?;
+    let mut png = Png::from_image(&image)?;
+
+    let at = png.chunk_position("IDAT").ok_or(PngError::ChunkNotFoundError)?;
+
+    let gamma = match colorspace {
+      QoiColorspace::Srgb => SRGB_GAMMA,
+      QoiColorspace::Linear => LINEAR_GAMMA,
+    };
+
+    png.insert_chunk(at, Chunk::new("gAMA".parse()?, gamma.to_be_bytes().to_vec()));
+
+    if colorspace == QoiColorspace::Srgb {
+      // perceptual rendering intent
+      png.insert_chunk(at, Chunk::new("sRGB".parse()?, vec![0]));
+    }
+
+    Ok(png)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_encode() {
+    let image = Image::new(4, 1, ColorType::Rgb, 8, vec![0, 0, 0, 0, 0, 0, 1, 0, 255, 0, 0, 0]).unwrap();
+    let bytes = image.to_qoi(QoiColorspace::Linear).unwrap();
+
+    assert_eq!(&bytes[..HEADER_LEN], b"qoif\0\0\0\x04\0\0\0\x01\x03\x01");
+    // a run of 2, then a diff of (1, 0, -1) and one of (-1, 0, 1)
+    assert_eq!(&bytes[HEADER_LEN..bytes.len() - END_MARKER.len()], &[0xc1, 0x79, 0x5b]);
+    assert!(bytes.ends_with(&END_MARKER));
+  }
+
+  #[test]
+  fn test_round_trip() {
+    // runs, repeated colors, small and large differences and alpha changes
+    let samples: Vec<u8> = (0..64u32 * 64)
+      .flat_map(|i| {
+        let v = if i % 7 < 3 { 10 } else { (i * 37 % 251) as u8 };
+        [v, v.wrapping_add((i % 5) as u8), v.wrapping_mul(3), if i % 11 == 0 { 128 } else { 255 }]
+      })
+      .collect();
+
+    let image = Image::new(64, 64, ColorType::RgbWithAlpha, 8, samples).unwrap();
+    let bytes = image.to_qoi(QoiColorspace::Srgb).unwrap();
+    let (decoded, colorspace) = Image::from_qoi(&bytes).unwrap();
+
+    assert!(decoded == image);
+    assert_eq!(colorspace, QoiColorspace::Srgb);
+
+    assert!(Image::from_qoi(&bytes[..bytes.len() - 20]).is_err());
+  }
+
+  #[test]
+  fn test_colorspace_chunks() {
+    let image = Image::new(1, 1, ColorType::Grayscale, 8, vec![200]).unwrap();
+
+    let linear = Png::from_qoi(&image.to_qoi(QoiColorspace::Linear).unwrap()).unwrap();
+    assert!(linear.chunk_position("sRGB").is_none());
+    assert_eq!(linear.qoi_colorspace(), QoiColorspace::Linear);
+
+    let srgb = Png::from_qoi(&image.to_qoi(QoiColorspace::Srgb).unwrap()).unwrap();
+    assert!(srgb.chunk_position("sRGB").is_some());
+    assert_eq!(srgb.qoi_colorspace(), QoiColorspace::Srgb);
+
+    assert_eq!(Png::from_image(&image).unwrap().qoi_colorspace(), QoiColorspace::Srgb);
+    assert_eq!(srgb.to_qoi(Some(QoiColorspace::Linear)).unwrap()[13], 1);
+  }
+}
@@ -0,0 +1,271 @@
+// Color quantization into palette images, with median cut in linear light
+
+use std::collections::{HashMap, HashSet};
+
+use crate::chunk::palette::{ChunkPalette, Palette};
+use crate::color_type::ColorType;
+use crate::convert::{palette_transparency, scale_sample};
+use crate::error::PngError;
+use crate::image::Image;
+
+/// Max entries of a PLTE chunk
+pub const MAX_PALETTE_ENTRIES: usize = 256;
+
+/// Alpha errors are more visible than color errors of the same size
+const ALPHA_WEIGHT: f32 = 2.0;
+
+/// A distinct 8 bit RGBA color, in linear light, and how many pixels use it
+#[derive(Clone, Copy)]
+struct WeightedColor {
+  linear: [f32; 4],
+  count: u32,
+}
+
+fn srgb_to_linear(v: u8) -> f32 {
+  let c = v as f32 / 255.0;
+
+  if c <= 0.04045 {
+    c / 12.92
+  } else {
+    ((c + 0.055) / 1.055).powf(2.4)
+  }
+}
+
+fn linear_to_srgb(v: f32) -> u8 {
+  let c = if v <= 0.003_130_8 {
+    v * 12.92
+  } else {
+    1.055 * v.powf(1.0 / 2.4) - 0.055
+  };
+
+  (c * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// Color channels are gamma decoded, alpha is already linear
+fn to_linear(color: &[u8; 4], lut: &[f32; 256]) -> [f32; 4] {
+  [lut[color[0] as usize], lut[color[1] as usize], lut[color[2] as usize], color[3] as f32 / 255.0]
+}
+
+fn from_linear(linear: &[f32; 4]) -> [u8; 4] {
+  [
+    linear_to_srgb(linear[0]),
+    linear_to_srgb(linear[1]),
+    linear_to_srgb(linear[2]),
+    (linear[3] * 255.0).round().clamp(0.0, 255.0) as u8,
+  ]
+}
+
+fn channel_weight(channel: usize) -> f32 {
+  if channel == 3 { ALPHA_WEIGHT } else { 1.0 }
+}
+
+fn distance(a: &[f32; 4], b: &[f32; 4]) -> f32 {
+  (0..4).map(|c| (a[c] - b[c]) * (a[c] - b[c]) * channel_weight(c) * channel_weight(c)).sum()
+}
+
+/// Channel with the widest weighted range in `colors`, and that range
+fn widest_channel(colors: &[WeightedColor]) -> (usize, f32) {
+  (0..4)
+    .map(|c| {
+      let (min, max) = colors.iter().fold((f32::MAX, f32::MIN), |(min, max), color| {
+        (min.min(color.linear[c]), max.max(color.linear[c]))
+      });
+      (c, (max - min) * channel_weight(c))
+    })
+    .max_by(|a, b| a.1.total_cmp(&b.1))
+    .unwrap()
+}
+
+/// Pixel weighted mean of `colors`
+fn mean(colors: &[WeightedColor]) -> [f32; 4] {
+  let total: f64 = colors.iter().map(|color| color.count as f64).sum();
+  let mut sum = [0f64; 4];
+
+  for color in colors {
+    for (c, s) in sum.iter_mut().enumerate() {
+      *s += color.linear[c] as f64 * color.count as f64;
+    }
+  }
+
+  sum.map(|s| (s / total) as f32)
+}
+
+/// Split the boxes at the pixel weighted median of their widest channel,
+/// always picking the box with the largest range times pixel count, until there are `max_colors` boxes
+fn median_cut(colors: Vec<WeightedColor>, max_colors: usize) -> Vec<[f32; 4]> {
+  let mut boxes: Vec<Vec<WeightedColor>> = vec![colors];
+
+  while boxes.len() < max_colors {
+    let candidate = boxes.iter()
+      .enumerate()
+      .filter(|(_, b)| b.len() > 1)
+      .map(|(i, b)| {
+        let (_, range) = widest_channel(b);
+        let pixels: u64 = b.iter().map(|color| color.count as u64).sum();
+        (i, range as f64 * pixels as f64)
+      })
+      .filter(|(_, score)| *score > 0.0)
+      .max_by(|a, b| a.1.total_cmp(&b.1));
+
+    let Some((index, _)) = candidate else {
+      break;
+    };
+
+    let mut colors = boxes.swap_remove(index);
+    let (channel, _) = widest_channel(&colors);
+    colors.sort_by(|a, b| a.linear[channel].total_cmp(&b.linear[channel]));
+
+    let half: u64 = colors.iter().map(|color| color.count as u64).sum::<u64>() / 2;
+    let mut acc = 0u64;
+    let mut split = colors.len() - 1;
+
+    for (i, color) in colors.iter().enumerate() {
+      acc += color.count as u64;
+      if acc > half {
+        split = i;
+        break;
+      }
+    }
+
+    let split = split.clamp(1, colors.len() - 1);
+    let upper = colors.split_off(split);
+
+    boxes.push(colors);
+    boxes.push(upper);
+  }
+
+  boxes.iter().map(|b| mean(b)).collect()
+}
+
+impl Image {
+  /// Quantize into a 8 bit palette image of at most `max_colors` entries.
+  /// Images that already fit are indexed exactly; otherwise colors are reduced with median cut
+  /// in linear light. Entries with alpha come first, so that tRNS can be truncated.
+  pub fn quantize(&self, max_colors: usize) -> Result<Image, PngError> {
+    if max_colors == 0 || max_colors > MAX_PALETTE_ENTRIES {
+      return Err(PngError::TooManyColors)
+    }
+
+    let lut: [f32; 256] = std::array::from_fn(|v| srgb_to_linear(v as u8));
+
+    // fully transparent pixels all look the same
+    let colors: Vec<[u8; 4]> = self.to_rgba16()?
+      .iter()
+      .map(|pixel| pixel.map(|v| scale_sample(v, 16, 8) as u8))
+      .map(|color| if color[3] == 0 { [0; 4] } else { color })
+      .collect();
+
+    let mut histogram: HashMap<[u8; 4], u32> = HashMap::new();
+
+    for color in colors.iter() {
+      *histogram.entry(*color).or_insert(0) += 1;
+    }
+
+    let mut entries: Vec<[u8; 4]> = if histogram.len() <= max_colors {
+      let mut exact: Vec<([u8; 4], u32)> = histogram.iter().map(|(&color, &count)| (color, count)).collect();
+      exact.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+      exact.into_iter().map(|(color, _)| color).collect()
+    } else {
+      // sorted, so that the result does not depend on the hash order
+      let mut distinct: Vec<([u8; 4], u32)> = histogram.iter().map(|(&color, &count)| (color, count)).collect();
+      distinct.sort();
+
+      let weighted: Vec<WeightedColor> = distinct.iter()
+        .map(|(color, count)| WeightedColor { linear: to_linear(color, &lut), count: *count })
+        .collect();
+
+      let mut seen: HashSet<[u8; 4]> = HashSet::new();
+      let mut entries: Vec<[u8; 4]> = median_cut(weighted, max_colors).iter().map(from_linear).collect();
+      entries.retain(|entry| seen.insert(*entry));
+      entries
+    };
+
+    // stable, so the most used colors stay first within each group
+    entries.sort_by_key(|entry| entry[3] == 255);
+
+    let linear_entries: Vec<[f32; 4]> = entries.iter().map(|entry| to_linear(entry, &lut)).collect();
+    let mut lookup: HashMap<[u8; 4], u8> = HashMap::with_capacity(histogram.len());
+
+    let samples: Vec<u8> = colors.iter()
+      .map(|color| {
+        *lookup.entry(*color).or_insert_with(|| {
+          let linear = to_linear(color, &lut);
+
+          linear_entries.iter()
+            .enumerate()
+            .min_by(|a, b| distance(a.1, &linear).total_cmp(&distance(b.1, &linear)))
+            .map(|(i, _)| i as u8)
+            .unwrap()
+        })
+      })
+      .collect();
+
+    let mut image = Image::new(self.width(), self.height(), ColorType::PaletteIndex, 8, samples)?;
+
+    image.set_palette(Some(ChunkPalette::new(
+      entries.iter().map(|&[r, g, b, _]| Palette::new(r, g, b)).collect()
+    )));
+    image.set_transparency(palette_transparency(&entries));
+
+    Ok(image)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn gradient() -> Image {
+    let samples: Vec<u8> = (0..64u32 * 64)
+      .flat_map(|i| {
+        let (x, y) = (i % 64, i / 64);
+        [(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8, if x < 8 { 100 } else { 255 }]
+      })
+      .collect();
+
+    Image::new(64, 64, ColorType::RgbWithAlpha, 8, samples).unwrap()
+  }
+
+  #[test]
+  fn test_srgb_linear_round_trip() {
+    for v in 0..=255u8 {
+      assert_eq!(linear_to_srgb(srgb_to_linear(v)), v);
+    }
+  }
+
+  #[test]
+  fn test_quantize_exact() {
+    let samples = vec![10, 20, 30, 40, 50, 60, 10, 20, 30];
+    let image = Image::new(3, 1, ColorType::Rgb, 8, samples).unwrap();
+
+    let indexed = image.quantize(256).unwrap();
+
+    assert_eq!(indexed.palette().unwrap().len(), 2);
+    assert!(indexed.transparency().is_none());
+    assert_eq!(indexed.convert(ColorType::Rgb, 8).unwrap().samples(), image.samples());
+  }
+
+  #[test]
+  fn test_quantize_median_cut() {
+    let image = gradient();
+    let indexed = image.quantize(16).unwrap();
+    let palette = indexed.palette().unwrap();
+
+    assert!(indexed.color_type() == ColorType::PaletteIndex);
+    assert!(palette.len() <= 16);
+    assert!(indexed.samples().iter().all(|&i| (i as usize) < palette.len()));
+
+    // translucent entries are first, and only they are in tRNS
+    let trns = indexed.transparency().unwrap();
+    let translucent = trns.get_transparency(&ColorType::PaletteIndex, 0).unwrap();
+    assert!(*translucent < 255);
+    assert_eq!(indexed.convert(ColorType::RgbWithAlpha, 8).unwrap().sample(0, 3), 100);
+    assert_eq!(indexed.convert(ColorType::RgbWithAlpha, 8).unwrap().sample(63, 3), 255);
+  }
+
+  #[test]
+  fn test_quantize_invalid_colors() {
+    assert!(gradient().quantize(0).is_err());
+    assert!(gradient().quantize(257).is_err());
+  }
+}
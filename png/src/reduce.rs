@@ -0,0 +1,233 @@
+// Lossless color type and bit depth reduction
+
+use std::collections::HashSet;
+
+use crate::chunk::Chunk;
+use crate::color_type::ColorType;
+use crate::convert::{color_key, scale_sample};
+use crate::error::PngError;
+use crate::image::Image;
+use crate::quantize::MAX_PALETTE_ENTRIES;
+use crate::{Png, UnsafeChunks};
+
+/// What the samples of an image allow
+struct Analysis {
+  opaque: bool,
+  gray: bool,
+  /// Every sample survives 16 -> 8 bit
+  fits_8_bit: bool,
+  /// Smallest bit depth holding every gray sample exactly
+  gray_bit_depth: u8,
+  /// Distinct colors, `None` once there are more than a palette holds
+  colors: Option<usize>,
+}
+
+fn fits_bit_depth(value: u16, bit_depth: u8) -> bool {
+  scale_sample(scale_sample(value, 16, bit_depth), bit_depth, 16) == value
+}
+
+fn analyze(pixels: &[[u16; 4]]) -> Analysis {
+  let opaque = pixels.iter().all(|p| p[3] == u16::MAX);
+  let gray = pixels.iter().all(|p| p[0] == p[1] && p[1] == p[2]);
+  let fits_8_bit = pixels.iter().all(|p| p.iter().all(|&v| fits_bit_depth(v, 8)));
+
+  let gray_bit_depth = [1, 2, 4, 8]
+    .into_iter()
+    .find(|&depth| pixels.iter().all(|p| fits_bit_depth(p[0], depth)))
+    .unwrap_or(16);
+
+  let mut distinct: HashSet<[u16; 4]> = HashSet::new();
+  let colors = pixels.iter()
+    .all(|pixel| {
+      distinct.insert(*pixel);
+      distinct.len() <= MAX_PALETTE_ENTRIES
+    })
+    .then_some(distinct.len());
+
+  Analysis { opaque, gray, fits_8_bit, gray_bit_depth, colors }
+}
+
+/// Smallest palette bit depth indexing `colors` entries
+fn palette_bit_depth(colors: usize) -> u8 {
+  [1, 2, 4, 8].into_iter().find(|&depth| colors <= 1 << depth).unwrap_or(8)
+}
+
+/// Estimated bytes of the raw scanlines plus PLTE and tRNS
+fn cost(width: u32, height: u32, color_type: &ColorType, bit_depth: u8, palette_entries: usize) -> usize {
+  let row = (width as usize * color_type.channels() as usize * bit_depth as usize).div_ceil(8) + 1;
+  let palette = if *color_type == ColorType::PaletteIndex { palette_entries * 4 } else { 0 };
+
+  row * height as usize + palette
+}
+
+impl Image {
+  /// The cheapest lossless color type and bit depth for these samples:
+  /// alpha is dropped when always opaque, or replaced by a tRNS color key when fully transparent pixels share a color no opaque pixel has,
+  /// RGB becomes grayscale when every pixel is gray, up to 256 colors become a palette,
+  /// 16 bit drops to 8 bit when the low bytes repeat the high bytes,
+  /// and grayscale and palette images use the smallest sufficient sub-byte depth.
+  /// The current format is kept unless another is strictly cheaper.
+  pub fn reduced_format(&self) -> Result<(ColorType, u8), PngError> {
+    let pixels = self.to_rgba16()?;
+    let analysis = analyze(&pixels);
+    let depth = if analysis.fits_8_bit { 8 } else { 16 };
+    let colors = analysis.colors.unwrap_or(MAX_PALETTE_ENTRIES);
+
+    let current_entries = self.palette().map(|palette| palette.len()).unwrap_or(colors);
+    let mut candidates: Vec<(ColorType, u8, usize)> = vec![(self.color_type(), self.bit_depth(), current_entries)];
+
+    match (analysis.gray, analysis.opaque) {
+      (true, true) => candidates.push((ColorType::Grayscale, analysis.gray_bit_depth, colors)),
+      (true, false) => candidates.push((ColorType::GrayscaleWithAlpha, depth, colors)),
+      (false, true) => candidates.push((ColorType::Rgb, depth, colors)),
+      (false, false) => candidates.push((ColorType::RgbWithAlpha, depth, colors)),
+    }
+
+    if !analysis.opaque {
+      let (color_type, bit_depth) = match analysis.gray {
+        true => (ColorType::Grayscale, analysis.gray_bit_depth),
+        false => (ColorType::Rgb, depth),
+      };
+
+      if color_key(&pixels, &color_type, bit_depth).is_some() {
+        candidates.push((color_type, bit_depth, colors));
+      }
+    }
+
+    if let (true, Some(colors)) = (analysis.fits_8_bit, analysis.colors) {
+      candidates.push((ColorType::PaletteIndex, palette_bit_depth(colors), colors));
+    }
+
+    // the first candidate wins ties, so the format only changes, and palettes are only used, when strictly smaller
+    let (color_type, bit_depth, _) = candidates.into_iter()
+      .min_by_key(|(color_type, bit_depth, entries)| cost(self.width(), self.height(), color_type, *bit_depth, *entries))
+      .unwrap();
+
+    Ok((color_type, bit_depth))
+  }
+
+  /// Convert into the format of `reduced_format`
+  pub fn reduce(&self) -> Result<Image, PngError> {
+    let (color_type, bit_depth) = self.reduced_format()?;
+
+    if color_type == self.color_type() && bit_depth == self.bit_depth() {
+      return Ok(self.clone())
+    }
+
+    self.convert(color_type, bit_depth)
+  }
+}
+
+impl Png {
+  /// Re-encode the image data in its cheapest lossless format, returning the chunks dropped by `set_image`.
+  /// Returns `None`, leaving the chunks untouched, if the format is already the cheapest.
+  pub fn reduce(&mut self, unsafe_chunks: UnsafeChunks) -> Result<Option<Vec<Chunk>>, PngError> {
+    let image = self.image()?;
+    let reduced = image.reduce()?;
+
+    if reduced.color_type() == image.color_type() && reduced.bit_depth() == image.bit_depth() {
+      return Ok(None)
+    }
+
+    self.set_image(&reduced, unsafe_chunks).map(Some)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn format(image: &Image) -> (ColorType, u8) {
+    image.reduced_format().unwrap()
+  }
+
+  #[test]
+  fn test_opaque_rgba_to_rgb() {
+    let samples: Vec<u8> = (0..300u32).flat_map(|i| [i as u8, (i * 3) as u8, (i * 7) as u8, 255]).collect();
+    let image = Image::new(300, 1, ColorType::RgbWithAlpha, 8, samples).unwrap();
+
+    assert!(format(&image) == (ColorType::Rgb, 8));
+  }
+
+  #[test]
+  fn test_gray_rgb_to_sub_byte_gray() {
+    let samples = vec![0, 0, 0, 255, 255, 255, 85, 85, 85, 170, 170, 170];
+    let image = Image::new(4, 1, ColorType::Rgb, 8, samples).unwrap();
+
+    assert!(format(&image) == (ColorType::Grayscale, 2));
+
+    let reduced = image.reduce().unwrap();
+    assert_eq!(reduced.samples(), &[0, 3, 1, 2]);
+    assert_eq!(reduced.convert(ColorType::Rgb, 8).unwrap().samples(), image.samples());
+  }
+
+  #[test]
+  fn test_few_colors_to_palette() {
+    let colors = [[200, 10, 10], [10, 200, 10], [10, 10, 200]];
+    let samples: Vec<u8> = (0..64).flat_map(|i| colors[i % 3]).collect();
+    let image = Image::new(8, 8, ColorType::Rgb, 8, samples).unwrap();
+
+    assert!(format(&image) == (ColorType::PaletteIndex, 2));
+    assert_eq!(image.reduce().unwrap().convert(ColorType::Rgb, 8).unwrap().samples(), image.samples());
+  }
+
+  #[test]
+  fn test_16_bit_to_8_bit() {
+    let samples: Vec<u8> = (0..300u32).flat_map(|i| [i as u8, i as u8, (i * 5) as u8, (i * 5) as u8, 9, 9]).collect();
+    let image = Image::new(300, 1, ColorType::Rgb, 16, samples.clone()).unwrap();
+
+    assert!(format(&image) == (ColorType::Rgb, 8));
+
+    let mut lossy = samples;
+    lossy[1] = 0x12;
+    let image = Image::new(300, 1, ColorType::Rgb, 16, lossy).unwrap();
+
+    assert!(format(&image) == (ColorType::Rgb, 16));
+  }
+
+  #[test]
+  fn test_png_reduce() {
+    let samples: Vec<u8> = (0..16).flat_map(|i| [i * 17, i * 17, i * 17, 255]).collect();
+    let image = Image::new(4, 4, ColorType::RgbWithAlpha, 8, samples).unwrap();
+    let mut png = Png::from_image(&image).unwrap();
+
+    assert!(png.reduce(UnsafeChunks::Discard).unwrap().is_some());
+
+    let header = png.header_chunk().unwrap();
+    assert!(header.color_type() == ColorType::Grayscale);
+    assert_eq!(header.bit_depth(), 4);
+    assert_eq!(png.image().unwrap().convert(ColorType::RgbWithAlpha, 8).unwrap().samples(), image.samples());
+
+    assert!(png.reduce(UnsafeChunks::Discard).unwrap().is_none());
+  }
+
+  #[test]
+  fn test_color_key() {
+    // more colors than a palette holds, with transparent pixels sharing black
+    let samples: Vec<u8> = (0..300u32)
+      .flat_map(|i| match i % 10 {
+        0 => [0, 0, 0, 0],
+        _ => [i as u8, (i * 3) as u8, 200, 255],
+      })
+      .collect();
+    let image = Image::new(300, 1, ColorType::RgbWithAlpha, 8, samples).unwrap();
+
+    assert!(format(&image) == (ColorType::Rgb, 8));
+
+    let reduced = image.reduce().unwrap();
+    assert_eq!(reduced.transparency().unwrap().color_key(&ColorType::Rgb), Some(vec![0, 0, 0]));
+    assert_eq!(reduced.convert(ColorType::RgbWithAlpha, 8).unwrap().samples(), image.samples());
+
+    // an input already using a color key stays as it is
+    let mut png = Png::from_image(&reduced).unwrap();
+    assert!(png.reduce(UnsafeChunks::Discard).unwrap().is_none());
+
+    // an opaque pixel of the transparent color rules the key out
+    let mut samples = image.into_samples();
+    samples[7] = 255;
+    samples[4..7].copy_from_slice(&[0, 0, 0]);
+    let image = Image::new(300, 1, ColorType::RgbWithAlpha, 8, samples).unwrap();
+
+    assert!(format(&image) == (ColorType::RgbWithAlpha, 8));
+  }
+}
@@ -0,0 +1,377 @@
+// Fixing the structural damage that strict decoders reject
+
+use crate::check::{self, RawChunk, AFTER_PALETTE, BEFORE_IMAGE_DATA, BEFORE_PALETTE};
+use crate::chunk::image_header::{ChunkImageHeader, IMAGE_HEADER_CHUNK_DATA_LEN};
+use crate::chunk::{Chunk, ChunkData, ChunkType};
+use crate::codec;
+use crate::error::PngError;
+use crate::Png;
+
+/// Zlib header for deflate with a 32K window and default compression
+const ZLIB_HEADER: [u8; 2] = [0x78, 0x9c];
+
+/// The repaired PNG and a description of every change made to it
+pub struct Repaired {
+  pub png: Png,
+  pub log: Vec<String>,
+}
+
+fn is(chunk: &Chunk, chunk_type: &str) -> bool {
+  chunk.chunk_type().to_string() == chunk_type
+}
+
+fn position(chunks: &[Chunk], chunk_type: &str) -> Option<usize> {
+  chunks.iter().position(|chunk| is(chunk, chunk_type))
+}
+
+/// Where a chunk was in the original file
+fn at(chunk: &Chunk) -> String {
+  match chunk.offset() {
+    Some(offset) => format!("{} at offset {}", chunk.chunk_type(), offset),
+    None => chunk.chunk_type().to_string(),
+  }
+}
+
+/// Chunk of the repaired PNG for `raw`, with its CRC recomputed.
+/// `None` if its type is not made of letters, or if it is a malformed IHDR after the first.
+fn rebuild(raw: &RawChunk, first_header: bool, log: &mut Vec<String>) -> Result<Option<Chunk>, PngError> {
+  let Ok(chunk_type) = ChunkType::try_from(raw.chunk_type) else {
+    log.push(format!("Dropped the chunk at offset {} with invalid type {:?}", raw.offset, raw.name()));
+    return Ok(None)
+  };
+
+  if raw.is("IHDR") {
+    let header = <[u8; IMAGE_HEADER_CHUNK_DATA_LEN]>::try_from(raw.data)
+      .map_err(|_| PngError::ChunkParseError)
+      .and_then(ChunkImageHeader::try_from);
+
+    match header {
+      Ok(_) => {},
+      // a broken header leaves nothing to decode the image with
+      Err(e) if first_header => return Err(e),
+      Err(_) => {
+        log.push(format!("Dropped the malformed duplicate IHDR at offset {}", raw.offset));
+        return Ok(None)
+      },
+    }
+  }
+
+  if raw.crc != raw.computed_crc() {
+    log.push(format!("Recomputed the CRC of {} at offset {}: {:08x} -> {:08x}", raw.name(), raw.offset, raw.crc, raw.computed_crc()));
+  }
+
+  let mut chunk = Chunk::new(chunk_type, raw.data.to_vec());
+  chunk.set_offset(raw.offset);
+
+  Ok(Some(chunk))
+}
+
+/// Keep only the first IHDR, at the start
+fn fix_header(chunks: &mut Vec<Chunk>, log: &mut Vec<String>) -> Result<(), PngError> {
+  let first = position(chunks, "IHDR").ok_or(PngError::ChunkNotFoundError)?;
+
+  let mut seen = false;
+  chunks.retain(|chunk| {
+    let duplicate = seen && is(chunk, "IHDR");
+    seen |= is(chunk, "IHDR");
+
+    if duplicate {
+      log.push(format!("Dropped the duplicate {}", at(chunk)));
+    }
+
+    !duplicate
+  });
+
+  if first != 0 {
+    let header = chunks.remove(first);
+    log.push(format!("Moved {} to the start", at(&header)));
+    chunks.insert(0, header);
+  }
+
+  Ok(())
+}
+
+/// Keep only the first IEND, at the end, adding it if missing
+fn fix_end(chunks: &mut Vec<Chunk>, log: &mut Vec<String>) {
+  let Some(first) = position(chunks, "IEND") else {
+    chunks.push(Chunk::new("IEND".parse().unwrap(), vec![]));
+    log.push(String::from("Appended the missing IEND chunk"));
+    return
+  };
+
+  let end = chunks.remove(first);
+
+  chunks.retain(|chunk| {
+    let duplicate = is(chunk, "IEND");
+
+    if duplicate {
+      log.push(format!("Dropped the duplicate {}", at(chunk)));
+    }
+
+    !duplicate
+  });
+
+  if first < chunks.len() {
+    log.push(format!("Moved {} to the end, after {} chunks that followed it", at(&end), chunks.len() - first));
+  }
+
+  chunks.push(end);
+}
+
+/// Gather the IDAT chunks into one run where the first one is, keeping the order of the other chunks
+fn merge_image_data(chunks: &mut Vec<Chunk>, log: &mut Vec<String>) {
+  let Some(start) = position(chunks, "IDAT") else { return };
+
+  let run = chunks[start..].iter().take_while(|chunk| is(chunk, "IDAT")).count();
+
+  if !chunks[start + run..].iter().any(|chunk| is(chunk, "IDAT")) {
+    return
+  }
+
+  let end = chunks.iter().rposition(|chunk| is(chunk, "IDAT")).unwrap();
+  let interleaved: Vec<String> = chunks[start..end].iter().filter(|chunk| !is(chunk, "IDAT")).map(at).collect();
+
+  let (image_data, rest): (Vec<Chunk>, Vec<Chunk>) = chunks.drain(start..).partition(|chunk| is(chunk, "IDAT"));
+
+  log.push(format!("Merged {} IDAT chunks into one run, moving {} after it", image_data.len(), interleaved.join(", ")));
+
+  chunks.extend(image_data);
+  chunks.extend(rest);
+}
+
+/// Wrap image data that is a bare deflate stream in a zlib header and Adler-32 checksum
+fn wrap_raw_deflate(chunks: &mut Vec<Chunk>, log: &mut Vec<String>) {
+  let Some(start) = position(chunks, "IDAT") else { return };
+
+  let compressed: Vec<u8> = chunks.iter().filter(|chunk| is(chunk, "IDAT")).flat_map(|chunk| chunk.data()).collect();
+
+  if check::zlib_header_problems(&compressed).is_empty() {
+    return
+  }
+
+  let limit = match chunks[0].chunk_data() {
+    ChunkData::ImageHeader(header) => codec::image_data_len(header).saturating_add(1),
+    _ => return,
+  };
+
+  let (data, consumed, error) = check::inflate_raw(&compressed, limit);
+
+  if error.is_some() || consumed != compressed.len() {
+    return
+  }
+
+  let wrapped: Vec<u8> = ZLIB_HEADER.into_iter()
+    .chain(compressed)
+    .chain(check::adler32(&data).to_be_bytes())
+    .collect();
+
+  let count = chunks.iter().filter(|chunk| is(chunk, "IDAT")).count();
+  chunks.retain(|chunk| !is(chunk, "IDAT"));
+  chunks.insert(start, Chunk::new("IDAT".parse().unwrap(), wrapped));
+
+  log.push(format!("Wrapped the raw deflate data of {} IDAT chunks in a zlib header and Adler-32 checksum", count));
+}
+
+/// Move PLTE and the ancillary chunks with ordering rules to the nearest legal position
+fn move_misplaced(chunks: &mut Vec<Chunk>, log: &mut Vec<String>) {
+  let Some(image_data) = position(chunks, "IDAT") else { return };
+
+  if let Some(palette) = position(chunks, "PLTE").filter(|&palette| palette > image_data) {
+    let chunk = chunks.remove(palette);
+    log.push(format!("Moved {} before IDAT", at(&chunk)));
+    chunks.insert(image_data, chunk);
+  }
+
+  let is_any = |chunk: &Chunk, chunk_types: &[&str]| chunk_types.iter().any(|chunk_type| is(chunk, chunk_type));
+
+  // the palette chunks, or IDAT without a palette
+  let before_palette = |chunks: &[Chunk]| position(chunks, "PLTE").or_else(|| position(chunks, "IDAT")).unwrap();
+  let before_image_data = |chunks: &[Chunk]| position(chunks, "IDAT").unwrap();
+
+  let palette = position(chunks, "PLTE");
+  let image_data = before_image_data(chunks);
+
+  let misplaced = |i: usize, chunk: &Chunk| {
+    (is_any(chunk, &BEFORE_PALETTE) && i > before_palette(chunks))
+      || (is_any(chunk, &AFTER_PALETTE) && (i > image_data || palette.is_some_and(|palette| i < palette)))
+      || (is_any(chunk, &BEFORE_IMAGE_DATA) && i > image_data)
+  };
+
+  let indices: Vec<usize> = chunks.iter().enumerate().filter(|(i, chunk)| misplaced(*i, chunk)).map(|(i, _)| i).collect();
+
+  let moved: Vec<Chunk> = indices.into_iter().rev().map(|i| chunks.remove(i)).collect();
+
+  for chunk in moved.into_iter().rev() {
+    let (to, place) = match is_any(&chunk, &BEFORE_PALETTE) {
+      true if palette.is_some() => (before_palette(chunks), "before PLTE"),
+      true => (before_palette(chunks), "before IDAT"),
+      false if is_any(&chunk, &AFTER_PALETTE) && palette.is_some() => (before_image_data(chunks), "between PLTE and IDAT"),
+      false => (before_image_data(chunks), "before IDAT"),
+    };
+
+    log.push(format!("Moved {} {}", at(&chunk), place));
+    chunks.insert(to, chunk);
+  }
+}
+
+/// Repair the encoded PNG `bytes`: recompute wrong CRCs, drop bytes after IEND and truncated chunks,
+/// move IEND to the end or add it if missing, gather split IDAT runs, wrap raw deflate image data in zlib
+/// and move misplaced chunks.
+/// Fails if the signature or IHDR is missing or invalid, as nothing can be decoded then.
+pub fn repair(bytes: &[u8]) -> Result<Repaired, PngError> {
+  if !bytes.starts_with(&Png::SIGNATURE) {
+    return Err(PngError::InvalidHeader)
+  }
+
+  let mut log = vec![];
+  let scan = check::scan(bytes);
+
+  if scan.end < bytes.len() {
+    match scan.chunks.iter().any(|chunk| chunk.is("IEND")) {
+      true => log.push(format!("Dropped {} bytes after IEND", bytes.len() - scan.end)),
+      false => log.push(format!("Dropped the truncated chunk at offset {}, {} bytes", scan.end, bytes.len() - scan.end)),
+    }
+  }
+
+  let mut chunks = vec![];
+
+  let first_header = scan.chunks.iter().position(|raw| raw.is("IHDR"));
+
+  for (i, raw) in scan.chunks.iter().enumerate() {
+    if let Some(chunk) = rebuild(raw, first_header == Some(i), &mut log)? {
+      chunks.push(chunk);
+    }
+  }
+
+  fix_header(&mut chunks, &mut log)?;
+  fix_end(&mut chunks, &mut log);
+  merge_image_data(&mut chunks, &mut log);
+  wrap_raw_deflate(&mut chunks, &mut log);
+  move_misplaced(&mut chunks, &mut log);
+
+  Ok(Repaired { png: Png::from_chunks(chunks), log })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::color_type::ColorType;
+  use crate::image::Image;
+
+  fn testing_png() -> Png {
+    let image = Image::new(4, 3, ColorType::Rgb, 8, (0..36).collect()).unwrap();
+    Png::from_image(&image).unwrap()
+  }
+
+  fn chunk_types(png: &Png) -> Vec<String> {
+    png.chunks().iter().map(|chunk| chunk.chunk_type().to_string()).collect()
+  }
+
+  #[test]
+  fn test_nothing_to_repair() {
+    let bytes = testing_png().as_bytes();
+    let repaired = repair(&bytes).unwrap();
+
+    assert!(repaired.log.is_empty());
+    assert_eq!(repaired.png.as_bytes(), bytes);
+  }
+
+  #[test]
+  fn test_repair() {
+    let mut png = testing_png();
+    let compressed = png.compressed_data();
+    let (first, second) = compressed.split_at(compressed.len() / 2);
+
+    png.replace_chunks("IDAT", vec![
+      Chunk::new("IDAT".parse().unwrap(), first.to_vec()),
+      Chunk::new("tEXt".parse().unwrap(), b"Title\0split".to_vec()),
+      Chunk::new("IDAT".parse().unwrap(), second.to_vec()),
+      Chunk::new("gAMA".parse().unwrap(), vec![0, 0, 0xb1, 0x8f]),
+    ]).unwrap();
+    png.remove_chunks_by(|chunk| is(chunk, "IEND"));
+
+    let mut bytes = png.as_bytes();
+    // IHDR crc
+    bytes[8 + 8 + 13] ^= 1;
+
+    let repaired = repair(&bytes).unwrap();
+
+    assert_eq!(repaired.log.len(), 4);
+    assert!(repaired.log[0].starts_with("Recomputed the CRC of IHDR at offset 8"));
+    assert_eq!(repaired.log[1], "Appended the missing IEND chunk");
+    assert!(repaired.log[2].starts_with("Merged 2 IDAT chunks into one run, moving tEXt at offset"));
+    assert!(repaired.log[3].starts_with("Moved gAMA at offset"));
+
+    assert_eq!(chunk_types(&repaired.png), vec!["IHDR", "gAMA", "IDAT", "IDAT", "tEXt", "IEND"]);
+    assert!(check::check(&repaired.png.as_bytes()).is_empty());
+  }
+
+  #[test]
+  fn test_misplaced_end() {
+    let mut png = testing_png();
+    let compressed = png.compressed_data();
+    let (first, second) = compressed.split_at(compressed.len() / 2);
+
+    // IHDR IDAT IEND IDAT
+    png.replace_chunks("IDAT", vec![Chunk::new("IDAT".parse().unwrap(), first.to_vec())]).unwrap();
+    png.append_chunk(Chunk::new("IDAT".parse().unwrap(), second.to_vec()));
+
+    let image = png.image();
+    let repaired = repair(&png.as_bytes()).unwrap();
+
+    assert_eq!(repaired.log.len(), 1);
+    assert!(repaired.log[0].starts_with("Moved IEND at offset"));
+    assert_eq!(chunk_types(&repaired.png), vec!["IHDR", "IDAT", "IDAT", "IEND"]);
+    assert!(check::check(&repaired.png.as_bytes()).is_empty());
+    assert_eq!(repaired.png.image().unwrap().samples(), image.unwrap().samples());
+  }
+
+  #[test]
+  fn test_wrap_raw_deflate() {
+    let mut png = testing_png();
+    let compressed = png.compressed_data();
+    let deflated = compressed[2..compressed.len() - 4].to_vec();
+
+    png.replace_chunks("IDAT", vec![Chunk::new("IDAT".parse().unwrap(), deflated)]).unwrap();
+
+    let mut bytes = png.as_bytes();
+    bytes.extend_from_slice(b"garbage");
+
+    let repaired = repair(&bytes).unwrap();
+
+    assert_eq!(repaired.log, vec![
+      "Dropped 7 bytes after IEND",
+      "Wrapped the raw deflate data of 1 IDAT chunks in a zlib header and Adler-32 checksum",
+    ]);
+    assert!(check::check(&repaired.png.as_bytes()).is_empty());
+  }
+
+  #[test]
+  fn test_malformed_duplicate_header() {
+    let mut png = testing_png();
+    let header = png.chunks()[0].data();
+    png.insert_chunk(1, Chunk::new("IHDR".parse().unwrap(), header));
+
+    let mut bytes = png.as_bytes();
+    // color type of the duplicate
+    let duplicate = 8 + 25;
+    bytes[duplicate + 8 + 9] = 5;
+    let crc = crate::chunk::crc_of(b"IHDR", &bytes[duplicate + 8..duplicate + 8 + 13]);
+    bytes[duplicate + 8 + 13..duplicate + 8 + 17].copy_from_slice(&crc.to_be_bytes());
+
+    let repaired = repair(&bytes).unwrap();
+
+    assert_eq!(repaired.log, vec![format!("Dropped the malformed duplicate IHDR at offset {}", duplicate)]);
+    assert_eq!(repaired.png.as_bytes(), testing_png().as_bytes());
+  }
+
+  #[test]
+  fn test_invalid_header() {
+    let mut bytes = testing_png().as_bytes();
+    // color type
+    bytes[8 + 8 + 9] = 5;
+
+    assert!(repair(&bytes).is_err());
+    assert!(repair(b"not a png").is_err());
+  }
+}
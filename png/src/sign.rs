@@ -0,0 +1,203 @@
+// Ed25519 signatures of message chunks, optionally covering the critical chunks too
+
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey};
+use ed25519_dalek::pkcs8::spki::der::pem::LineEnding;
+use ed25519_dalek::{Signature, Signer, Verifier};
+use rand_core::OsRng;
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+use crate::chunk::{Chunk, ChunkType};
+use crate::error::PngError;
+use crate::Png;
+
+/// Signature of a message only. Safe to copy, as it does not depend on the image
+pub const MESSAGE_SIGNATURE_CHUNK: &str = "sgNt";
+
+/// Signature of a message and the critical chunks. Unsafe to copy, so editors drop it with the image it covers
+pub const IMAGE_SIGNATURE_CHUNK: &str = "sgNT";
+
+/// Separates these signatures from any other use of the key
+const SIGNATURE_CONTEXT: &[u8] = b"png-me signature\0";
+
+/// Signature chunk layout: version, type of the signed chunks, then the 64 byte signature
+const SIGNATURE_VERSION: u8 = 1;
+const SIGNATURE_CHUNK_LEN: usize = 1 + 4 + Signature::BYTE_SIZE;
+
+/// New random key pair
+pub fn generate_key() -> SigningKey {
+  SigningKey::generate(&mut OsRng)
+}
+
+/// PKCS#8 PEM of the private key
+pub fn signing_key_to_pem(key: &SigningKey) -> Result<String, PngError> {
+  key.to_pkcs8_pem(LineEnding::LF)
+    .map(|pem| pem.to_string())
+    .map_err(|_| PngError::InvalidKey)
+}
+
+/// SPKI PEM of the public key
+pub fn verifying_key_to_pem(key: &VerifyingKey) -> Result<String, PngError> {
+  key.to_public_key_pem(LineEnding::LF).map_err(|_| PngError::InvalidKey)
+}
+
+pub fn signing_key_from_pem(pem: &str) -> Result<SigningKey, PngError> {
+  SigningKey::from_pkcs8_pem(pem).map_err(|_| PngError::InvalidKey)
+}
+
+pub fn verifying_key_from_pem(pem: &str) -> Result<VerifyingKey, PngError> {
+  VerifyingKey::from_public_key_pem(pem).map_err(|_| PngError::InvalidKey)
+}
+
+fn covers_critical(signature_chunk_type: &str) -> bool {
+  signature_chunk_type == IMAGE_SIGNATURE_CHUNK
+}
+
+impl Png {
+  /// Bytes covered by a signature of the `chunk_type` chunks: every chunk of the type, in order,
+  /// followed by the critical chunks if `with_critical`
+  fn signed_bytes(&self, chunk_type: &ChunkType, with_critical: bool) -> Result<Vec<u8>, PngError> {
+    let message_chunks = self.get_chunks(&chunk_type.to_string());
+
+    if message_chunks.is_empty() {
+      return Err(PngError::ChunkNotFoundError)
+    }
+
+    let mut bytes = SIGNATURE_CONTEXT.to_vec();
+    bytes.push(SIGNATURE_VERSION);
+    bytes.push(with_critical as u8);
+
+    let critical_chunks = self.chunks().iter().filter(|chunk| with_critical && chunk.chunk_type().is_critical());
+
+    for chunk in message_chunks.into_iter().chain(critical_chunks) {
+      bytes.extend_from_slice(&chunk.chunk_type().bytes());
+      bytes.extend_from_slice(&chunk.length().to_be_bytes());
+      bytes.extend(chunk.data());
+    }
+
+    Ok(bytes)
+  }
+
+  /// Signature chunks of the `chunk_type` chunks, with the type of each
+  fn signature_chunks(&self, chunk_type: &ChunkType) -> Vec<(&'static str, &Chunk)> {
+    [MESSAGE_SIGNATURE_CHUNK, IMAGE_SIGNATURE_CHUNK]
+      .into_iter()
+      .flat_map(|signature_type| self.get_chunks(signature_type).into_iter().map(move |chunk| (signature_type, chunk)))
+      .filter(|(_, chunk)| chunk.data().get(1..5) == Some(&chunk_type.bytes()[..]))
+      .collect()
+  }
+
+  /// Sign every chunk of `chunk_type`, and the critical chunks if `with_critical`.
+  /// Previous signatures of these chunks are replaced; the new one is stored after the last signed chunk.
+  pub fn sign(&mut self, chunk_type: &str, key: &SigningKey, with_critical: bool) -> Result<(), PngError> {
+    let chunk_type: ChunkType = chunk_type.parse()?;
+    let signature = key.sign(&self.signed_bytes(&chunk_type, with_critical)?);
+
+    let signature_type = if with_critical { IMAGE_SIGNATURE_CHUNK } else { MESSAGE_SIGNATURE_CHUNK };
+
+    let mut data = Vec::with_capacity(SIGNATURE_CHUNK_LEN);
+    data.push(SIGNATURE_VERSION);
+    data.extend_from_slice(&chunk_type.bytes());
+    data.extend_from_slice(&signature.to_bytes());
+
+    self.remove_chunks_by(|chunk| {
+      let t = chunk.chunk_type().to_string();
+      (t == MESSAGE_SIGNATURE_CHUNK || t == IMAGE_SIGNATURE_CHUNK) && chunk.data().get(1..5) == Some(&chunk_type.bytes()[..])
+    });
+
+    let pos = self.chunks().iter()
+      .rposition(|chunk| *chunk.chunk_type() == chunk_type)
+      .ok_or(PngError::ChunkNotFoundError)?;
+
+    self.insert_chunk(pos + 1, Chunk::new(signature_type.parse()?, data));
+
+    Ok(())
+  }
+
+  /// Verify the signature of the `chunk_type` chunks with `key`.
+  /// Returns whether the signature covers the critical chunks too.
+  pub fn verify(&self, chunk_type: &str, key: &VerifyingKey) -> Result<bool, PngError> {
+    let chunk_type: ChunkType = chunk_type.parse()?;
+    let signatures = self.signature_chunks(&chunk_type);
+
+    let [(signature_type, chunk)] = signatures[..] else {
+      return Err(if signatures.is_empty() { PngError::SignatureNotFound } else { PngError::InvalidSignature })
+    };
+
+    let data = chunk.data();
+
+    if data.len() != SIGNATURE_CHUNK_LEN || data[0] != SIGNATURE_VERSION {
+      return Err(PngError::InvalidSignature)
+    }
+
+    let signature = Signature::from_slice(&data[5..]).map_err(|_| PngError::InvalidSignature)?;
+    let with_critical = covers_critical(signature_type);
+
+    key.verify(&self.signed_bytes(&chunk_type, with_critical)?, &signature)
+      .map_err(|_| PngError::InvalidSignature)?;
+
+    Ok(with_critical)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::color_type::ColorType;
+  use crate::image::Image;
+  use crate::UnsafeChunks;
+
+  fn signed_png(with_critical: bool, key: &SigningKey) -> Png {
+    let image = Image::new(2, 2, ColorType::Grayscale, 8, vec![0, 64, 128, 255]).unwrap();
+    let mut png = Png::from_image(&image).unwrap();
+    png.replace_chunks("ruSt", vec![Chunk::new("ruSt".parse().unwrap(), b"from us".to_vec())]).unwrap();
+    png.sign("ruSt", key, with_critical).unwrap();
+    png
+  }
+
+  #[test]
+  fn test_sign_and_verify() {
+    let key = generate_key();
+    let png = signed_png(false, &key);
+
+    assert_eq!(png.chunk_position(MESSAGE_SIGNATURE_CHUNK), Some(png.chunk_position("ruSt").unwrap() + 1));
+    assert!(!png.verify("ruSt", &key.verifying_key()).unwrap());
+    assert!(matches!(png.verify("ruSt", &generate_key().verifying_key()), Err(PngError::InvalidSignature)));
+    assert!(matches!(png.verify("abCd", &key.verifying_key()), Err(PngError::SignatureNotFound)));
+  }
+
+  #[test]
+  fn test_altered_message_fails() {
+    let key = generate_key();
+    let mut png = signed_png(false, &key);
+
+    png.get_chunk_mut("ruSt").unwrap().set_data(b"from them");
+
+    assert!(matches!(png.verify("ruSt", &key.verifying_key()), Err(PngError::InvalidSignature)));
+  }
+
+  #[test]
+  fn test_altered_pixels_fail() {
+    let key = generate_key();
+    let mut png = signed_png(true, &key);
+    assert!(png.verify("ruSt", &key.verifying_key()).unwrap());
+
+    // keep the signature chunk, as an editor unaware of it might
+    let altered = Image::new(2, 2, ColorType::Grayscale, 8, vec![0, 64, 128, 254]).unwrap();
+    png.set_image(&altered, UnsafeChunks::Keep).unwrap();
+
+    assert!(matches!(png.verify("ruSt", &key.verifying_key()), Err(PngError::InvalidSignature)));
+  }
+
+  #[test]
+  fn test_key_pem_round_trip() {
+    let key = generate_key();
+
+    let private = signing_key_from_pem(&signing_key_to_pem(&key).unwrap()).unwrap();
+    let public = verifying_key_from_pem(&verifying_key_to_pem(&key.verifying_key()).unwrap()).unwrap();
+
+    assert_eq!(private.to_bytes(), key.to_bytes());
+    assert_eq!(public, key.verifying_key());
+    assert!(signing_key_from_pem("not a key").is_err());
+  }
+}
@@ -0,0 +1,250 @@
+// Payloads hidden in the least significant bits of the samples
+
+use rand_chacha::ChaCha20Rng;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use sha2::{Digest, Sha256};
+
+use crate::color_type::ColorType;
+use crate::error::PngError;
+use crate::image::Image;
+
+/// Hidden layout: payload length and CRC-32 (u32, big endian), then the payload
+const HEADER_LEN: usize = 8;
+
+/// Separates the pixel order seed from any other use of the key
+const ORDER_CONTEXT: &[u8] = b"png-me lsb order\0";
+
+const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+  Red,
+  Green,
+  Blue,
+  Gray,
+  Alpha,
+}
+
+impl Channel {
+  /// Index of the channel in the samples of a pixel of `color_type`
+  fn index(&self, color_type: &ColorType) -> Option<usize> {
+    match (color_type, self) {
+      (ColorType::Grayscale | ColorType::GrayscaleWithAlpha, Channel::Gray) => Some(0),
+      (ColorType::GrayscaleWithAlpha, Channel::Alpha) => Some(1),
+      (ColorType::Rgb | ColorType::RgbWithAlpha, Channel::Red) => Some(0),
+      (ColorType::Rgb | ColorType::RgbWithAlpha, Channel::Green) => Some(1),
+      (ColorType::Rgb | ColorType::RgbWithAlpha, Channel::Blue) => Some(2),
+      (ColorType::RgbWithAlpha, Channel::Alpha) => Some(3),
+      _ => None,
+    }
+  }
+
+  /// The color channels of `color_type`, alpha left out as changes to it show on any background
+  pub fn color_channels(color_type: &ColorType) -> Vec<Channel> {
+    match color_type {
+      ColorType::Grayscale | ColorType::GrayscaleWithAlpha => vec![Channel::Gray],
+      _ => vec![Channel::Red, Channel::Green, Channel::Blue],
+    }
+  }
+}
+
+/// Where the payload bits go
+pub struct LsbOptions {
+  /// Low bits used in each sample, up to the bit depth and at most 8
+  pub bits: u8,
+  /// Channels carrying bits, the color channels if empty
+  pub channels: Vec<Channel>,
+  /// Shuffles the pixel order; without it the pixels are used in scanline order
+  pub key: Option<String>,
+}
+
+impl Default for LsbOptions {
+  fn default() -> Self {
+    Self { bits: 1, channels: vec![], key: None }
+  }
+}
+
+impl Image {
+  /// Sample indices carrying the bits, in the order they are used
+  fn lsb_slots(&self, options: &LsbOptions) -> Result<Vec<usize>, PngError> {
+    let color_type = self.color_type();
+
+    if color_type == ColorType::PaletteIndex {
+      return Err(PngError::InvalidColorType)
+    }
+
+    if options.bits == 0 || options.bits > self.bit_depth().min(8) {
+      return Err(PngError::InvalidBitDepth)
+    }
+
+    let channels = match options.channels.is_empty() {
+      true => Channel::color_channels(&color_type),
+      false => options.channels.clone(),
+    };
+
+    let mut indices: Vec<usize> = channels.iter()
+      .map(|channel| channel.index(&color_type).ok_or(PngError::InvalidChannel))
+      .collect::<Result<_, _>>()?;
+    indices.sort_unstable();
+    indices.dedup();
+
+    let mut pixels: Vec<usize> = (0..self.pixel_count()).collect();
+
+    if let Some(key) = &options.key {
+      let seed: [u8; 32] = Sha256::new()
+        .chain_update(ORDER_CONTEXT)
+        .chain_update(key.as_bytes())
+        .finalize()
+        .into();
+      let mut rng = ChaCha20Rng::from_seed(seed);
+
+      // Fisher-Yates
+      for i in (1..pixels.len()).rev() {
+        let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+        pixels.swap(i, j);
+      }
+    }
+
+    let channel_count = self.channels();
+
+    Ok(pixels.iter().flat_map(|pixel| indices.iter().map(move |c| pixel * channel_count + c)).collect())
+  }
+
+  /// Payload bytes that fit with `options`
+  pub fn lsb_capacity(&self, options: &LsbOptions) -> Result<usize, PngError> {
+    let bits = self.lsb_slots(options)?.len() * options.bits as usize;
+
+    Ok((bits / 8).saturating_sub(HEADER_LEN))
+  }
+
+  /// Copy of the image with `payload` hidden in the low bits of the samples
+  pub fn embed_lsb(&self, payload: &[u8], options: &LsbOptions) -> Result<Image, PngError> {
+    let slots = self.lsb_slots(options)?;
+    let capacity = (slots.len() * options.bits as usize / 8).saturating_sub(HEADER_LEN);
+
+    if payload.len() > capacity {
+      return Err(PngError::PayloadTooLarge(capacity))
+    }
+
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+    data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    data.extend_from_slice(&CRC32.checksum(payload).to_be_bytes());
+    data.extend_from_slice(payload);
+
+    let bits = options.bits as usize;
+    let mask = (1u16 << bits) - 1;
+    let mut image = self.clone();
+
+    for (n, &slot) in slots.iter().enumerate().take((data.len() * 8).div_ceil(bits)) {
+      let value = (0..bits).fold(0u16, |value, b| (value << 1) | bit(&data, n * bits + b) as u16);
+      let sample = self.sample(slot / self.channels(), slot % self.channels());
+
+      image.set_sample(slot / self.channels(), slot % self.channels(), (sample & !mask) | value);
+    }
+
+    Ok(image)
+  }
+
+  /// The payload hidden by `embed_lsb` with the same options
+  pub fn extract_lsb(&self, options: &LsbOptions) -> Result<Vec<u8>, PngError> {
+    let slots = self.lsb_slots(options)?;
+    let bits = options.bits as usize;
+    let mask = (1u16 << bits) - 1;
+
+    let read = |len: usize| -> Vec<u8> {
+      let mut data = vec![0u8; len];
+
+      for (n, &slot) in slots.iter().enumerate().take((len * 8).div_ceil(bits)) {
+        let value = self.sample(slot / self.channels(), slot % self.channels()) & mask;
+
+        for b in 0..bits {
+          let at = n * bits + b;
+
+          if at < len * 8 && (value >> (bits - 1 - b)) & 1 == 1 {
+            data[at / 8] |= 0x80 >> (at % 8);
+          }
+        }
+      }
+
+      data
+    };
+
+    let capacity = (slots.len() * bits / 8).saturating_sub(HEADER_LEN);
+    let header = read(HEADER_LEN);
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let crc = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+
+    if len > capacity {
+      return Err(PngError::HiddenPayloadNotFound)
+    }
+
+    let payload = read(HEADER_LEN + len).split_off(HEADER_LEN);
+
+    if CRC32.checksum(&payload) != crc {
+      return Err(PngError::HiddenPayloadNotFound)
+    }
+
+    Ok(payload)
+  }
+}
+
+/// Bit `at` of `data`, most significant first
+fn bit(data: &[u8], at: usize) -> u8 {
+  data.get(at / 8).map_or(0, |byte| (byte >> (7 - at % 8)) & 1)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rgba_image() -> Image {
+    let samples: Vec<u8> = (0..40u32 * 30).flat_map(|i| [(i * 7) as u8, (i * 3) as u8, (i / 5) as u8, 255]).collect();
+    Image::new(40, 30, ColorType::RgbWithAlpha, 8, samples).unwrap()
+  }
+
+  #[test]
+  fn test_capacity() {
+    let image = rgba_image();
+
+    assert_eq!(image.lsb_capacity(&LsbOptions::default()).unwrap(), 40 * 30 * 3 / 8 - HEADER_LEN);
+
+    let options = LsbOptions { bits: 2, channels: vec![Channel::Red, Channel::Alpha], key: None };
+    assert_eq!(image.lsb_capacity(&options).unwrap(), 40 * 30 * 4 / 8 - HEADER_LEN);
+
+    let options = LsbOptions { bits: 1, channels: vec![Channel::Gray], key: None };
+    assert!(matches!(image.lsb_capacity(&options), Err(PngError::InvalidChannel)));
+  }
+
+  #[test]
+  fn test_embed_extract() {
+    let image = rgba_image();
+    let options = LsbOptions { bits: 2, channels: vec![Channel::Green, Channel::Blue], key: Some(String::from("k")) };
+
+    let stego = image.embed_lsb(b"meet at noon", &options).unwrap();
+
+    assert_eq!(stego.extract_lsb(&options).unwrap(), b"meet at noon");
+    assert!(stego.samples().iter().zip(image.samples()).all(|(a, b)| a & !3 == b & !3));
+    // red and alpha are untouched
+    assert!((0..image.pixel_count()).all(|i| stego.sample(i, 0) == image.sample(i, 0) && stego.sample(i, 3) == 255));
+
+    let wrong_key = LsbOptions { key: Some(String::from("j")), ..options };
+    assert!(matches!(stego.extract_lsb(&wrong_key), Err(PngError::HiddenPayloadNotFound)));
+  }
+
+  #[test]
+  fn test_embed_16_bit_gray() {
+    let image = Image::new(160, 1, ColorType::Grayscale, 16, vec![0xab; 320]).unwrap();
+    let options = LsbOptions::default();
+
+    let stego = image.embed_lsb(b"ok", &options).unwrap();
+
+    assert_eq!(stego.extract_lsb(&options).unwrap(), b"ok");
+    assert!(matches!(image.embed_lsb(b"thirteen byte", &options), Err(PngError::PayloadTooLarge(12))));
+  }
+
+  #[test]
+  fn test_palette_is_rejected() {
+    let image = Image::new(1, 1, ColorType::PaletteIndex, 8, vec![0]).unwrap();
+    assert!(matches!(image.lsb_capacity(&LsbOptions::default()), Err(PngError::InvalidColorType)));
+  }
+}
@@ -0,0 +1,90 @@
+// Metadata removal
+
+use crate::chunk::{Chunk, ChunkType};
+use crate::Png;
+
+/// Public ancillary chunks that change how the image is displayed, kept unless all ancillary chunks are dropped
+pub const RENDERING_CHUNKS: [&str; 9] = ["tRNS", "gAMA", "cHRM", "sRGB", "iCCP", "sBIT", "bKGD", "hIST", "pHYs"];
+
+/// Which ancillary chunks `Png::strip` keeps. Critical chunks are always kept.
+#[derive(Default)]
+pub struct StripOptions {
+  /// Chunk types that are always kept
+  pub keep: Vec<ChunkType>,
+  /// Drop the rendering chunks too, not only the metadata
+  pub drop_all_ancillary: bool,
+  /// Keep every chunk marked safe to copy
+  pub keep_safe_to_copy: bool,
+}
+
+impl StripOptions {
+  fn keeps(&self, chunk_type: &ChunkType) -> bool {
+    if chunk_type.is_critical() || self.keep.contains(chunk_type) {
+      return true
+    }
+
+    if self.keep_safe_to_copy && chunk_type.is_safe_to_copy() {
+      return true
+    }
+
+    !self.drop_all_ancillary && RENDERING_CHUNKS.contains(&chunk_type.to_string().as_str())
+  }
+}
+
+impl Png {
+  /// Remove the ancillary chunks `options` does not keep: text, time, Exif and private chunks by default,
+  /// or every ancillary chunk with `drop_all_ancillary`. Returns the removed chunks in order.
+  pub fn strip(&mut self, options: &StripOptions) -> Vec<Chunk> {
+    self.remove_chunks_by(|chunk| !options.keeps(chunk.chunk_type()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::str::FromStr;
+  use crate::color_type::ColorType;
+  use crate::image::Image;
+
+  fn chunk(chunk_type: &str) -> Chunk {
+    Chunk::new(ChunkType::from_str(chunk_type).unwrap(), b"Author\0me".to_vec())
+  }
+
+  fn testing_png() -> Png {
+    let image = Image::new(1, 1, ColorType::Grayscale, 8, vec![0]).unwrap();
+    let mut png = Png::from_image(&image).unwrap();
+
+    png.insert_chunk(1, chunk("gAMA"));
+    png.insert_chunk(2, chunk("tEXt"));
+    png.insert_chunk(4, chunk("ruSt"));
+    png.insert_chunk(5, chunk("ruST"));
+    png
+  }
+
+  fn chunk_types(chunks: &[Chunk]) -> Vec<String> {
+    chunks.iter().map(|c| c.chunk_type().to_string()).collect()
+  }
+
+  #[test]
+  fn test_strip_metadata() {
+    let mut png = testing_png();
+    let removed = png.strip(&StripOptions::default());
+
+    assert_eq!(chunk_types(&removed), vec!["tEXt", "ruSt", "ruST"]);
+    assert_eq!(chunk_types(png.chunks()), vec!["IHDR", "gAMA", "IDAT", "IEND"]);
+  }
+
+  #[test]
+  fn test_strip_all_ancillary_with_keep() {
+    let mut png = testing_png();
+    let options = StripOptions {
+      keep: vec![ChunkType::from_str("tEXt").unwrap()],
+      drop_all_ancillary: true,
+      keep_safe_to_copy: true,
+    };
+    let removed = png.strip(&options);
+
+    assert_eq!(chunk_types(&removed), vec!["gAMA", "ruST"]);
+    assert_eq!(chunk_types(png.chunks()), vec!["IHDR", "tEXt", "IDAT", "ruSt", "IEND"]);
+  }
+}
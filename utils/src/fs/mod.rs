@@ -1,21 +1,233 @@
-use std::fs::File;
-use std::io::{BufReader, Read, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Cursor, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub fn read_file_buffer(filepath: &str) -> Vec<u8> {
-  let file = File::open(filepath).expect("Open file error");
+/// Path standing for stdin when read and for stdout when written
+pub const STDIO_PATH: &str = "-";
+
+/// Extension of the copies kept by `backup`
+pub const BACKUP_EXTENSION: &str = "bak";
+
+/// Distinguishes the temp files of concurrent writes
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub trait ReadSeek: Read + Seek {}
 
-  let buffer_size = 1024;
+impl<T: Read + Seek> ReadSeek for T {}
+
+fn is_stdio(path: &Path) -> bool {
+  path == Path::new(STDIO_PATH)
+}
+
+/// Prefix the message of an error with the path it happened on
+fn at_path(path: &Path) -> impl FnOnce(io::Error) -> io::Error + '_ {
+  move |e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
 
-  let mut reader = BufReader::with_capacity(buffer_size, file);
+/// Whole content of the file at `path`, or of stdin for `-`
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+  let path = path.as_ref();
   let mut buffer: Vec<u8> = vec![];
 
-  reader.read_to_end(&mut buffer).expect("Read buffer error");
+  if is_stdio(path) {
+    io::stdin().lock().read_to_end(&mut buffer).map_err(at_path(Path::new("stdin")))?;
+    return Ok(buffer);
+  }
+
+  File::open(path)
+    .and_then(|file| BufReader::new(file).read_to_end(&mut buffer))
+    .map_err(at_path(path))?;
+
+  Ok(buffer)
+}
+
+/// Buffered reader of the file at `path`. Stdin, read with `-`, can not seek and is buffered whole in memory.
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn ReadSeek>> {
+  let path = path.as_ref();
+
+  if is_stdio(path) {
+    return Ok(Box::new(Cursor::new(read(path)?)));
+  }
+
+  let file = File::open(path).map_err(at_path(path))?;
+
+  Ok(Box::new(BufReader::new(file)))
+}
+
+/// Temp file next to `path`, so that renaming it over `path` stays on the same file system
+fn temp_path_for(path: &Path) -> PathBuf {
+  let name = path.file_name().map_or(String::from("file"), |name| name.to_string_lossy().into_owned());
+  let count = TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+  path.with_file_name(format!(".{}.{}.{}.tmp", name, process::id(), count))
+}
+
+/// Write `buf` to `path` through a temp file renamed over it, so that the file is either
+/// left untouched or fully replaced. The permissions of a replaced file are kept. `-` writes stdout.
+pub fn write_atomic<P: AsRef<Path>>(path: P, buf: &[u8]) -> io::Result<()> {
+  let path = path.as_ref();
+
+  if is_stdio(path) {
+    let mut stdout = io::stdout().lock();
+    return stdout.write_all(buf).and_then(|_| stdout.flush()).map_err(at_path(Path::new("stdout")));
+  }
+
+  // replace the target of a symlink, not the link
+  let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+  let temp_path = temp_path_for(&path);
+
+  let mut file = File::create(&temp_path).map_err(at_path(&temp_path))?;
+
+  if let Ok(metadata) = fs::metadata(&path) {
+    let _ = file.set_permissions(metadata.permissions());
+  }
 
-  buffer
+  let written = file.write_all(buf)
+    .and_then(|_| file.sync_all())
+    .and_then(|_| fs::rename(&temp_path, &path));
+
+  if written.is_err() {
+    let _ = fs::remove_file(&temp_path);
+  }
+
+  written.map_err(at_path(&path))
+}
+
+/// Write `buf` to a new file at `path`, failing if it exists. With `owner_only`, the file is
+/// readable and writable by its owner only from the start.
+pub fn write_new<P: AsRef<Path>>(path: P, buf: &[u8], owner_only: bool) -> io::Result<()> {
+  let path = path.as_ref();
+  let mut options = OpenOptions::new();
+  options.write(true).create_new(true);
+
+  #[cfg(unix)]
+  if owner_only {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600);
+  }
+
+  #[cfg(not(unix))]
+  let _ = owner_only;
+
+  options.open(path)
+    .and_then(|mut file| file.write_all(buf))
+    .map_err(at_path(path))
 }
 
+/// Copy the file at `path` to `<path>.bak`, returning the path of the copy
+pub fn backup<P: AsRef<Path>>(path: P) -> io::Result<PathBuf> {
+  let path = path.as_ref();
+
+  let mut backup_path = path.as_os_str().to_owned();
+  backup_path.push(".");
+  backup_path.push(BACKUP_EXTENSION);
+  let backup_path = PathBuf::from(backup_path);
+
+  fs::copy(path, &backup_path).map_err(at_path(path))?;
+
+  Ok(backup_path)
+}
+
+pub fn create_dir_all<P: AsRef<Path>>(path: P) -> io::Result<()> {
+  let path = path.as_ref();
+
+  fs::create_dir_all(path).map_err(at_path(path))
+}
+
+/// Entries of the directory at `path`, in name order
+pub fn read_dir<P: AsRef<Path>>(path: P) -> io::Result<Vec<fs::DirEntry>> {
+  let path = path.as_ref();
+
+  let mut entries = fs::read_dir(path)
+    .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+    .map_err(at_path(path))?;
+  entries.sort_by_key(|entry| entry.file_name());
+
+  Ok(entries)
+}
+
+#[deprecated(note = "use `read`, which returns the error instead of panicking")]
+pub fn read_file_buffer(filepath: &str) -> Vec<u8> {
+  read(filepath).expect("Read buffer error")
+}
+
+#[deprecated(note = "use `write_atomic`, which returns the error instead of panicking")]
 pub fn write_buffer_to_file(buf: &[u8], filepath: &str) {
-  let mut file = File::create(filepath).expect("Create file error");
+  write_atomic(filepath, buf).expect("Write buf to file error")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("utils-fs-{}-{}", name, process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn test_write_atomic_and_read() {
+    let dir = temp_dir("atomic");
+    let path = dir.join("a.png");
+
+    write_atomic(&path, b"first").unwrap();
+    write_atomic(&path, b"second").unwrap();
+
+    assert_eq!(read(&path).unwrap(), b"second");
+    // no temp file is left behind
+    assert_eq!(read_dir(&dir).unwrap().len(), 1);
+
+    let mut reader = open(&path).unwrap();
+    reader.seek(io::SeekFrom::Start(3)).unwrap();
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "ond");
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_errors_name_the_path() {
+    let e = read("no/such/file.png").unwrap_err();
+
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    assert!(e.to_string().starts_with("no/such/file.png: "));
+  }
+
+  #[test]
+  fn test_write_new_and_backup() {
+    let dir = temp_dir("new");
+    let path = dir.join("key");
+
+    write_new(&path, b"secret", true).unwrap();
+    assert_eq!(write_new(&path, b"other", true).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    let backup_path = backup(&path).unwrap();
+    assert_eq!(backup_path, dir.join("key.bak"));
+    assert_eq!(read(&backup_path).unwrap(), b"secret");
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  #[allow(deprecated)]
+  fn test_deprecated_wrappers() {
+    let dir = temp_dir("deprecated");
+    let path = dir.join("a.png");
+    let filepath = path.to_str().unwrap();
+
+    write_buffer_to_file(b"buffer", filepath);
+    assert_eq!(read_file_buffer(filepath), b"buffer");
 
-  file.write_all(buf).expect("Write buf to file error");
+    fs::remove_dir_all(&dir).unwrap();
+  }
 }